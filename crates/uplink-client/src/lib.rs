@@ -0,0 +1,180 @@
+//! uplink-client: async Rust clients for the uplink services
+//!
+//! [`PtyClient`] speaks the uplink-pty protocol: requests are `async` methods
+//! whose responses are matched up by request id, and events arrive on a
+//! [`Stream`](futures_util::Stream). Clients can reconnect on their own when
+//! the socket drops.
+//!
+//! uplink-fs lives outside this workspace, so there is no filesystem client
+//! here yet; the connection handling below is not pty-specific.
+
+pub mod pty;
+
+pub use pty::{PtyClient, PtyClientBuilder, PtyEvent};
+
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::net::unix::OwnedReadHalf;
+use tokio::sync::oneshot;
+use tokio_util::codec::FramedRead;
+use uplink_proto::{
+    ErrorResponse, Frame, FrameCodec, FrameWriter, PayloadCodec, ProtocolError, SendError, WireFormat, MSG_ERROR, MSG_PROTOCOL_ERROR,
+};
+
+/// Frames read from a service socket
+pub(crate) type Frames = FramedRead<OwnedReadHalf, FrameCodec>;
+
+/// A request failed or the connection is gone
+#[derive(Debug)]
+pub enum ClientError {
+    /// Connecting or reading failed
+    Io(std::io::Error),
+    /// A request could not be sent
+    Send(SendError),
+    /// A response could not be decoded
+    Decode(String),
+    /// The server answered with an error
+    Server(ErrorResponse),
+    /// The server could not process a frame at all
+    Protocol(ProtocolError),
+    /// The server sent something other than the expected response
+    Unexpected(u8),
+    /// The connection dropped before the response arrived
+    Disconnected,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "connection failed: {}", e),
+            ClientError::Send(e) => write!(f, "{}", e),
+            ClientError::Decode(e) => write!(f, "invalid response: {}", e),
+            ClientError::Server(e) => write!(f, "server error {}: {}", e.code, e.message),
+            ClientError::Protocol(e) => write!(f, "protocol error (tag {}): {}", e.tag, e.message),
+            ClientError::Unexpected(tag) => write!(f, "unexpected response (tag {})", tag),
+            ClientError::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<std::io::Error> for ClientError {
+    fn from(e: std::io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<SendError> for ClientError {
+    fn from(e: SendError) -> Self {
+        ClientError::Send(e)
+    }
+}
+
+/// Request ids and in-flight requests for one logical client, across reconnects
+pub(crate) struct Connection {
+    /// `None` while disconnected
+    writer: std::sync::Mutex<Option<FrameWriter>>,
+    /// Requests waiting for their response, keyed by id
+    pending: std::sync::Mutex<HashMap<u32, oneshot::Sender<Frame>>>,
+    next_id: AtomicU32,
+}
+
+impl Connection {
+    pub(crate) fn new() -> Self {
+        Self {
+            writer: std::sync::Mutex::new(None),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    pub(crate) fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Start sending on a socket that has finished its handshake
+    pub(crate) fn attach(&self, writer: FrameWriter) {
+        *self.writer.lock().unwrap_or_else(|e| e.into_inner()) = Some(writer);
+    }
+
+    /// Forget the socket; requests still waiting fail with [`ClientError::Disconnected`]
+    pub(crate) fn detach(&self) {
+        *self.writer.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// Send a message that gets no response
+    pub(crate) async fn send<T: Serialize>(&self, tag: u8, msg: &T) -> Result<(), ClientError> {
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let writer = writer.ok_or(ClientError::Disconnected)?;
+        Ok(uplink_proto::send_msg(&writer, tag, msg).await?)
+    }
+
+    /// Send the request `build` makes for a fresh id and wait for the frame
+    /// answering it; error responses come back as [`ClientError::Server`], and
+    /// protocol errors naming the request as [`ClientError::Protocol`]
+    pub(crate) async fn request<T: Serialize>(&self, tag: u8, build: impl FnOnce(u32) -> T) -> Result<Frame, ClientError> {
+        let id = self.next_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).insert(id, tx);
+        if let Err(e) = self.send(tag, &build(id)).await {
+            self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+            return Err(e);
+        }
+        let frame = rx.await.map_err(|_| ClientError::Disconnected)?;
+        match frame.tag {
+            MSG_ERROR => Err(ClientError::Server(decode(&frame)?)),
+            MSG_PROTOCOL_ERROR => Err(ClientError::Protocol(decode(&frame)?)),
+            _ => Ok(frame),
+        }
+    }
+
+    /// Hand a response to the request waiting for it; false if none is
+    pub(crate) fn resolve(&self, frame: Frame) -> bool {
+        let Some(id) = peek_id(&frame.payload) else {
+            return false;
+        };
+        match self.pending.lock().unwrap_or_else(|e| e.into_inner()).remove(&id) {
+            Some(tx) => tx.send(frame).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Decode a frame's payload; servers answer clients in MessagePack unless asked otherwise
+pub(crate) fn decode<T: DeserializeOwned>(frame: &Frame) -> Result<T, ClientError> {
+    frame.decode().map_err(|e| ClientError::Decode(e.0))
+}
+
+/// Decode a response that must have `tag`
+pub(crate) fn expect<T: DeserializeOwned>(frame: &Frame, tag: u8) -> Result<T, ClientError> {
+    if frame.tag != tag {
+        return Err(ClientError::Unexpected(frame.tag));
+    }
+    decode(frame)
+}
+
+/// Read the next frame while nothing else is reading, during a handshake
+pub(crate) async fn next_frame(frames: &mut Frames) -> Result<Frame, ClientError> {
+    match frames.next().await {
+        Some(Ok(frame)) if frame.tag == MSG_ERROR => Err(ClientError::Server(decode(&frame)?)),
+        Some(Ok(frame)) if frame.tag == MSG_PROTOCOL_ERROR => Err(ClientError::Protocol(decode(&frame)?)),
+        Some(Ok(frame)) => Ok(frame),
+        Some(Err(e)) => Err(ClientError::Io(e)),
+        None => Err(ClientError::Disconnected),
+    }
+}
+
+/// The `id` field of a response payload
+fn peek_id(payload: &[u8]) -> Option<u32> {
+    #[derive(Deserialize)]
+    struct ResponseId {
+        id: Option<u32>,
+    }
+    WireFormat::MessagePack.decode::<ResponseId>(payload).ok()?.id
+}
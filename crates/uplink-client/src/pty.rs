@@ -0,0 +1,403 @@
+//! Client for uplink-pty
+//!
+//! One task reads the socket: responses go to the request waiting on their id,
+//! events are broadcast to every [`PtyClient::events`] stream, and heartbeat
+//! pings are answered. With reconnection on, a dropped socket is redialled
+//! with backoff. Terminals belong to the connection that created them, so a
+//! [`PtyEvent::Reconnected`] means they are gone.
+
+use crate::{expect, next_frame, ClientError, Connection, Frames};
+use futures_util::Stream;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixStream;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::codec::FramedRead;
+use tracing::{debug, warn};
+use uplink_proto::{FrameCodec, FrameWriter, Hello, OkResponse, MSG_PROTOCOL_ERROR};
+use uplink_proto::pty::*;
+
+/// Events buffered per stream before a slow reader starts missing them
+const EVENT_BUFFER: usize = 1024;
+/// First delay before redialling a dropped socket, doubled after each failure
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+/// Capabilities the client handles
+const CLIENT_CAPABILITIES: u64 = CAP_EXIT_SIGNAL | CAP_HEARTBEAT | CAP_CANCEL | CAP_RESTART;
+
+/// Something the server sent unprompted
+#[derive(Debug, Clone)]
+pub enum PtyEvent {
+    Data(DataEvent),
+    Exit(ExitEvent),
+    Restarted(RestartedEvent),
+    Lifecycle(LifecycleEvent),
+    /// The socket dropped and a new connection was made; earlier terminals are gone
+    Reconnected,
+}
+
+/// Builder for [`PtyClient`]
+pub struct PtyClientBuilder {
+    path: PathBuf,
+    token: Option<String>,
+    reconnect: bool,
+}
+
+impl PtyClientBuilder {
+    /// Present `token` before anything else, for servers started with one
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Redial the socket whenever it drops
+    pub fn reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    pub async fn connect(self) -> Result<PtyClient, ClientError> {
+        let conn = Arc::new(Connection::new());
+        let capabilities = Arc::new(AtomicU64::new(0));
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        let options = Arc::new(self);
+
+        let frames = handshake(&options, &conn, &capabilities).await?;
+        let reader = tokio::spawn(read_loop(frames, options, conn.clone(), capabilities.clone(), events.clone()));
+        Ok(PtyClient { conn, capabilities, events, reader })
+    }
+}
+
+/// A connection to uplink-pty
+pub struct PtyClient {
+    conn: Arc<Connection>,
+    capabilities: Arc<AtomicU64>,
+    events: broadcast::Sender<PtyEvent>,
+    reader: JoinHandle<()>,
+}
+
+impl PtyClient {
+    pub fn builder(path: impl AsRef<Path>) -> PtyClientBuilder {
+        PtyClientBuilder { path: path.as_ref().to_path_buf(), token: None, reconnect: false }
+    }
+
+    /// Connect to the server at `path` without a token or reconnection
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, ClientError> {
+        Self::builder(path).connect().await
+    }
+
+    /// Capabilities negotiated with the server on the current connection
+    pub fn capabilities(&self) -> u64 {
+        self.capabilities.load(Ordering::Relaxed)
+    }
+
+    /// Events from now on; subscribe before creating a terminal to see all of its output
+    pub fn events(&self) -> impl Stream<Item = PtyEvent> + Send + 'static {
+        futures_util::stream::unfold(self.events.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "Event stream fell behind, events were dropped");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Start a terminal; `req.id` is replaced with a fresh request id
+    pub async fn create_terminal(&self, req: CreateRequest) -> Result<CreatedResponse, ClientError> {
+        let frame = self.conn.request(MSG_CREATE, |id| CreateRequest { id, ..req }).await?;
+        expect(&frame, MSG_CREATED)
+    }
+
+    pub async fn input(&self, terminal_id: u32, data: impl Into<Vec<u8>>) -> Result<(), ClientError> {
+        let data = data.into();
+        let frame = self.conn.request(MSG_INPUT, |id| InputRequest { id, terminal_id, data, timeout_ms: None }).await?;
+        expect::<OkResponse>(&frame, MSG_OK).map(drop)
+    }
+
+    pub async fn resize(&self, terminal_id: u32, cols: u16, rows: u16) -> Result<(), ClientError> {
+        let frame = self.conn.request(MSG_RESIZE, |id| ResizeRequest { id, terminal_id, cols, rows }).await?;
+        expect::<OkResponse>(&frame, MSG_OK).map(drop)
+    }
+
+    pub async fn kill(&self, terminal_id: u32) -> Result<(), ClientError> {
+        let frame = self.conn.request(MSG_KILL, |id| KillRequest { id, terminal_id }).await?;
+        expect::<OkResponse>(&frame, MSG_OK).map(drop)
+    }
+
+    /// The live environment of a terminal's process
+    pub async fn get_env(&self, terminal_id: u32) -> Result<HashMap<String, String>, ClientError> {
+        let frame = self.conn.request(MSG_GET_ENV, |id| GetEnvRequest { id, terminal_id }).await?;
+        expect::<EnvResponse>(&frame, MSG_ENV).map(|resp| resp.env)
+    }
+
+    pub async fn server_info(&self) -> Result<ServerInfoResponse, ClientError> {
+        let frame = self.conn.request(MSG_SERVER_INFO, |id| ServerInfoRequest { id }).await?;
+        expect(&frame, MSG_SERVER_INFO_RESULT)
+    }
+}
+
+impl Drop for PtyClient {
+    fn drop(&mut self) {
+        // Once the socket closes the server tears this client's terminals down
+        self.reader.abort();
+    }
+}
+
+/// Connect, authenticate and say Hello, leaving `conn` ready to send
+/// Requests only reach the socket once the Hello is acknowledged, so they can't
+/// overtake the handshake; until then they fail with [`ClientError::Disconnected`]
+async fn handshake(options: &PtyClientBuilder, conn: &Connection, capabilities: &AtomicU64) -> Result<Frames, ClientError> {
+    let (read, write) = UnixStream::connect(&options.path).await?.into_split();
+    let mut frames = FramedRead::new(read, FrameCodec::new());
+    let writer = FrameWriter::spawn(write);
+
+    if let Some(token) = &options.token {
+        let id = conn.next_id();
+        uplink_proto::send_msg(&writer, MSG_AUTH, &AuthRequest { id, token: token.clone() }).await?;
+        expect::<OkResponse>(&next_frame(&mut frames).await?, MSG_OK)?;
+    }
+
+    let hello = HelloRequest {
+        hello: Hello { id: conn.next_id(), version: PROTOCOL_VERSION, capabilities: CLIENT_CAPABILITIES },
+        minimal: false,
+        codec: None,
+    };
+    uplink_proto::send_msg(&writer, MSG_HELLO, &hello).await?;
+    let ack: HelloAck = expect(&next_frame(&mut frames).await?, MSG_HELLO_ACK)?;
+    debug!(version = ack.ack.version, capabilities = ack.ack.capabilities, server = %ack.ack.server, "Connected to uplink-pty");
+    capabilities.store(ack.ack.capabilities, Ordering::Relaxed);
+    conn.attach(writer);
+    Ok(frames)
+}
+
+/// Route everything the server sends until the socket drops for good
+async fn read_loop(
+    mut frames: Frames,
+    options: Arc<PtyClientBuilder>,
+    conn: Arc<Connection>,
+    capabilities: Arc<AtomicU64>,
+    events: broadcast::Sender<PtyEvent>,
+) {
+    loop {
+        while let Some(frame) = futures_util::StreamExt::next(&mut frames).await {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!(error = %e, "Failed to read from uplink-pty");
+                    break;
+                }
+            };
+            let event = match frame.tag {
+                MSG_DATA => expect(&frame, MSG_DATA).map(PtyEvent::Data),
+                MSG_EXIT => expect(&frame, MSG_EXIT).map(PtyEvent::Exit),
+                MSG_RESTARTED => expect(&frame, MSG_RESTARTED).map(PtyEvent::Restarted),
+                MSG_LIFECYCLE => expect(&frame, MSG_LIFECYCLE).map(PtyEvent::Lifecycle),
+                MSG_PING => {
+                    if let Ok(ping) = expect::<Ping>(&frame, MSG_PING) {
+                        let _ = conn.send(MSG_PONG, &Pong { seq: ping.seq }).await;
+                    }
+                    continue;
+                }
+                MSG_PONG => continue,
+                MSG_PROTOCOL_ERROR => {
+                    if let Ok(err) = expect::<ProtocolError>(&frame, MSG_PROTOCOL_ERROR) {
+                        // Errors that name a request fail that request instead
+                        if err.id.is_some() && conn.resolve(frame) {
+                            continue;
+                        }
+                        warn!(tag = err.tag, message = %err.message, "uplink-pty rejected a frame");
+                    }
+                    continue;
+                }
+                tag => {
+                    if !conn.resolve(frame) {
+                        debug!(tag, "Response for a request nobody is waiting on");
+                    }
+                    continue;
+                }
+            };
+            match event {
+                Ok(event) => {
+                    let _ = events.send(event);
+                }
+                Err(e) => warn!(error = %e, "Undecodable event"),
+            }
+        }
+
+        conn.detach();
+        if !options.reconnect {
+            return;
+        }
+        frames = redial(&options, &conn, &capabilities).await;
+        let _ = events.send(PtyEvent::Reconnected);
+    }
+}
+
+/// Keep trying to reconnect, backing off between attempts
+async fn redial(options: &PtyClientBuilder, conn: &Connection, capabilities: &AtomicU64) -> Frames {
+    let mut delay = RECONNECT_MIN_DELAY;
+    loop {
+        tokio::time::sleep(delay).await;
+        match handshake(options, conn, capabilities).await {
+            Ok(frames) => return frames,
+            Err(e) => {
+                debug!(error = %e, path = %options.path.display(), "Reconnect failed");
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use uplink_pty::hooks::TrustPolicy;
+    use uplink_pty::PtyServer;
+
+    /// Run `server` on a fresh socket named after `name`
+    async fn start(server: PtyServer, name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("uplink-client-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let endpoint = uplink_pty::Endpoint::Path(path.clone());
+        tokio::spawn(async move { server.run(endpoint).await });
+        while !path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        path
+    }
+
+    fn sh(command: &str) -> CreateRequest {
+        CreateRequest {
+            id: 0,
+            shell: "/bin/sh".into(),
+            args: vec!["-c".into(), command.into()],
+            cwd: "/".into(),
+            env: HashMap::new(),
+            cols: 80,
+            rows: 24,
+            restart_on_exit: false,
+            output_buffer: None,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_a_command_and_sees_it_exit() {
+        let server = PtyServer::builder().build();
+        let path = start(server.clone(), "run").await;
+
+        let client = PtyClient::connect(&path).await.unwrap();
+        assert_ne!(client.capabilities() & CAP_EXIT_SIGNAL, 0);
+        let mut events = Box::pin(client.events());
+        let created = client.create_terminal(sh("echo from-client")).await.unwrap();
+
+        let mut output = Vec::new();
+        let exit = loop {
+            match tokio::time::timeout(Duration::from_secs(10), events.next()).await.unwrap().unwrap() {
+                PtyEvent::Data(data) if data.terminal_id == created.terminal_id => output.extend(data.data),
+                PtyEvent::Exit(exit) if exit.terminal_id == created.terminal_id => break exit,
+                _ => {}
+            }
+        };
+        assert!(String::from_utf8_lossy(&output).contains("from-client"));
+        assert_eq!(exit.code, Some(0));
+
+        let err = client.get_env(created.terminal_id + 100).await.unwrap_err();
+        assert!(matches!(err, ClientError::Server(resp) if resp.error_code() == Some(ErrorCode::NotFound)));
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn confirmation_is_denied_to_clients_that_cannot_confirm() {
+        let server = PtyServer::builder().spawn_hook(TrustPolicy { confirm_all: true, ..TrustPolicy::default() }).build();
+        let path = start(server.clone(), "confirm").await;
+
+        let client = PtyClient::connect(&path).await.unwrap();
+        assert_eq!(client.capabilities() & CAP_CONFIRM, 0);
+        let result = tokio::time::timeout(Duration::from_secs(5), client.create_terminal(sh("true"))).await;
+        let err = result.expect("create must be answered, not left waiting on a prompt").unwrap_err();
+        assert!(matches!(err, ClientError::Server(resp) if resp.error_code() == Some(ErrorCode::Denied)));
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn cancelled_completion_is_never_answered() {
+        let server = PtyServer::builder().build();
+        let path = start(server.clone(), "cancel").await;
+        let client = PtyClient::connect(&path).await.unwrap();
+
+        // Raw requests, so the cancel can follow the completion on the wire
+        let complete = |cancel: bool| {
+            let conn = &client.conn;
+            async move {
+                let id = conn.next_id();
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                conn.pending.lock().unwrap().insert(id, tx);
+                conn.send(MSG_COMPLETE, &CompleteRequest { id, line: "ls /".into(), cwd: "/".into() }).await.unwrap();
+                if cancel {
+                    conn.send(MSG_CANCEL, &CancelRequest { id }).await.unwrap();
+                }
+                tokio::time::timeout(Duration::from_secs(3), rx).await
+            }
+        };
+        let answered = complete(false).await.expect("completion must be answered").unwrap();
+        assert_eq!(answered.tag, MSG_COMPLETIONS);
+        assert!(complete(true).await.is_err(), "cancelled completion must not be answered");
+        server.shutdown();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn requests_during_a_reconnect_do_not_disturb_the_handshake() {
+        let first = PtyServer::builder().auth_token("s3cret").build();
+        let path = start(first.clone(), "reconnect").await;
+        let client = Arc::new(PtyClient::builder(&path).token("s3cret").reconnect(true).connect().await.unwrap());
+        let mut events = Box::pin(client.events());
+
+        // Keep requests going while the socket is down and being redialled
+        let busy = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let (client, busy) = (client.clone(), busy.clone());
+                tokio::spawn(async move {
+                    while busy.load(Ordering::Relaxed) {
+                        let result = tokio::time::timeout(Duration::from_secs(5), client.server_info()).await;
+                        match result.expect("requests must not hang while reconnecting") {
+                            Ok(_) | Err(ClientError::Disconnected | ClientError::Send(_)) => {}
+                            Err(e) => panic!("unexpected error while reconnecting: {e}"),
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+
+        first.shutdown();
+        while path.exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let second = PtyServer::builder().auth_token("s3cret").build();
+        start(second.clone(), "reconnect").await;
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), events.next()).await.expect("client must reconnect");
+            if let Some(PtyEvent::Reconnected) = event {
+                break;
+            }
+        }
+
+        busy.store(false, Ordering::Relaxed);
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        client.server_info().await.unwrap();
+        second.shutdown();
+    }
+}
@@ -0,0 +1,137 @@
+//! `tokio_util` codec for uplink frames
+//!
+//! Decoding is delegated to a `LengthDelimitedCodec` configured for the
+//! [1 byte tag][4 byte length BE] header, so reads are buffered and partial
+//! frames are handled in one place.
+//!
+//! After [`CAP_MULTIPLEX`](crate::CAP_MULTIPLEX) is negotiated, switch the
+//! codec with [`FrameCodec::set_multiplexed`] so the service id is read and
+//! written too.
+//!
+//! Servers should cap the payload size with [`FrameCodec::with_max_frame_len`]:
+//! the length is checked as soon as the header arrives, before any buffer is
+//! sized for it.
+
+use crate::{Frame, FRAME_HEADER_LEN, MUX_FRAME_HEADER_LEN};
+use bytes::{BufMut, BytesMut};
+use std::fmt;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+/// Largest request payload servers accept unless configured otherwise
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Encoder/decoder pair for [`Frame`]s, for use with `Framed`, `FramedRead` or `FramedWrite`
+#[derive(Debug)]
+pub struct FrameCodec {
+    inner: LengthDelimitedCodec,
+    max_frame_len: usize,
+    multiplexed: bool,
+}
+
+/// A frame header announced a payload over the limit
+/// Decode errors carry it inside an `InvalidData` `io::Error`; see [`FrameTooLarge::from_io`]
+#[derive(Debug, Clone)]
+pub struct FrameTooLarge {
+    pub tag: u8,
+    pub len: usize,
+    pub max: usize,
+}
+
+impl FrameTooLarge {
+    /// The limit violation behind a decode error, if that's what it was
+    pub fn from_io(err: &std::io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame of {} bytes (tag {}) exceeds the {} byte limit", self.len, self.tag, self.max)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+impl FrameCodec {
+    /// A codec that accepts any frame the header can describe
+    pub fn new() -> Self {
+        Self::with_max_frame_len(u32::MAX as usize)
+    }
+
+    /// A codec that rejects payloads larger than `max_frame_len` bytes
+    pub fn with_max_frame_len(max_frame_len: usize) -> Self {
+        Self { inner: length_codec(FRAME_HEADER_LEN, max_frame_len), max_frame_len, multiplexed: false }
+    }
+
+    /// Read and write the service id byte from the next frame on
+    pub fn set_multiplexed(&mut self, multiplexed: bool) {
+        self.multiplexed = multiplexed;
+        self.inner = length_codec(self.header_len(), self.max_frame_len);
+    }
+
+    fn header_len(&self) -> usize {
+        if self.multiplexed { MUX_FRAME_HEADER_LEN } else { FRAME_HEADER_LEN }
+    }
+}
+
+/// A length codec for headers of `header_len` bytes ending in the u32 length
+fn length_codec(header_len: usize, max_frame_len: usize) -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .length_field_offset(header_len - 4)
+        .length_field_type::<u32>()
+        .big_endian()
+        .length_adjustment(header_len as isize) // The length covers only the payload
+        .num_skip(0) // Keep the header; the tag is needed
+        .max_frame_length(max_frame_len.saturating_add(header_len))
+        .new_codec()
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Self::Error> {
+        // The header stays in `src` until the whole frame is in, so this sees every frame
+        let header_len = self.header_len();
+        if src.len() >= header_len {
+            let at = header_len - 4;
+            let len = u32::from_be_bytes([src[at], src[at + 1], src[at + 2], src[at + 3]]) as usize;
+            if len > self.max_frame_len {
+                let err = FrameTooLarge { tag: src[0], len, max: self.max_frame_len };
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+            }
+        }
+        let Some(mut frame) = self.inner.decode(src)? else {
+            return Ok(None);
+        };
+        let (tag, service) = (frame[0], self.multiplexed.then(|| frame[1]));
+        let payload = frame.split_off(header_len).to_vec();
+        Ok(Some(Frame { tag, service, payload }))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let len = u32::try_from(frame.payload.len())
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame payload too large"))?;
+        dst.reserve(self.header_len() + frame.payload.len());
+        dst.put_u8(frame.tag);
+        if self.multiplexed {
+            let service = frame.service.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "multiplexed frame has no service id")
+            })?;
+            dst.put_u8(service);
+        }
+        dst.put_u32(len);
+        dst.put_slice(&frame.payload);
+        Ok(())
+    }
+}
@@ -0,0 +1,56 @@
+//! Optional zstd compression of bulk frames
+//!
+//! Once a client negotiates [`CAP_COMPRESSION`](crate::CAP_COMPRESSION), bulk
+//! frames (terminal output, file data) carry a flags byte ahead of the payload:
+//! [1 byte tag][4 byte length BE][1 byte flags][payload]. The length covers the
+//! flags byte, so framing is unchanged. With [`FLAG_ZSTD`] set the payload is a
+//! zstd frame holding the encoded message; small payloads are sent as-is.
+
+use crate::format::{PayloadCodec, WireFormat};
+use crate::{SendError, FRAME_HEADER_LEN};
+use serde::Serialize;
+use std::borrow::Cow;
+
+/// Flags bit: the payload is zstd-compressed
+pub const FLAG_ZSTD: u8 = 1 << 0;
+/// Payloads smaller than this are not worth compressing
+pub const DEFAULT_COMPRESS_THRESHOLD: usize = 512;
+/// Favour latency over ratio; terminal output is sent as it is produced
+const ZSTD_LEVEL: i32 = 1;
+
+/// Encode a bulk message as a flagged frame, compressing payloads of at least
+/// `threshold` bytes when that makes them smaller
+pub fn encode_flagged_frame<T: Serialize>(
+    format: WireFormat,
+    tag: u8,
+    msg: &T,
+    threshold: usize,
+) -> Result<Vec<u8>, SendError> {
+    let payload = format.encode(msg).map_err(|e| SendError::Serialize(e.0))?;
+    let compressed = (payload.len() >= threshold)
+        .then(|| zstd::bulk::compress(&payload, ZSTD_LEVEL).ok())
+        .flatten()
+        .filter(|c| c.len() < payload.len());
+    let (flags, body) = match &compressed {
+        Some(c) => (FLAG_ZSTD, c.as_slice()),
+        None => (0, payload.as_slice()),
+    };
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + 1 + body.len());
+    frame.push(tag);
+    frame.extend_from_slice(&(body.len() as u32 + 1).to_be_bytes());
+    frame.push(flags);
+    frame.extend_from_slice(body);
+    Ok(frame)
+}
+
+/// The encoded message inside a flagged payload, decompressed if need be
+pub fn unflag_payload(payload: &[u8]) -> std::io::Result<Cow<'_, [u8]>> {
+    let (&flags, body) = payload
+        .split_first()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "flagged payload is empty"))?;
+    if flags & FLAG_ZSTD != 0 {
+        Ok(Cow::Owned(zstd::stream::decode_all(body)?))
+    } else {
+        Ok(Cow::Borrowed(body))
+    }
+}
@@ -0,0 +1,147 @@
+//! Payload serialization formats
+//!
+//! Payloads are MessagePack (named fields) unless a client negotiates
+//! something else. CBOR is there for platforms with better CBOR tooling; JSON
+//! exists for debugging: a capture of a JSON session can be read with ordinary
+//! tools.
+//!
+//! Requests are always maps, so a server can tell the formats apart from the
+//! first byte with [`WireFormat::detect`]. That lets it read a Hello sent in
+//! a format it offers; once Hello has been answered, both sides use the
+//! negotiated format and a server refuses payloads in any other.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Turns messages into payload bytes and back
+pub trait PayloadCodec {
+    /// Append `msg` to `buf`
+    fn encode_into<T: Serialize + ?Sized>(&self, buf: &mut Vec<u8>, msg: &T) -> Result<(), FormatError>;
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, FormatError>;
+
+    fn encode<T: Serialize + ?Sized>(&self, msg: &T) -> Result<Vec<u8>, FormatError> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf, msg)?;
+        Ok(buf)
+    }
+}
+
+/// MessagePack with named fields, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+/// CBOR (RFC 8949), maps keyed by field name
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+/// JSON, for reading traffic while debugging
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl PayloadCodec for MessagePack {
+    fn encode_into<T: Serialize + ?Sized>(&self, buf: &mut Vec<u8>, msg: &T) -> Result<(), FormatError> {
+        rmp_serde::encode::write_named(buf, msg).map_err(|e| FormatError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, FormatError> {
+        rmp_serde::from_slice(payload).map_err(|e| FormatError(e.to_string()))
+    }
+}
+
+impl PayloadCodec for Cbor {
+    fn encode_into<T: Serialize + ?Sized>(&self, buf: &mut Vec<u8>, msg: &T) -> Result<(), FormatError> {
+        ciborium::into_writer(msg, buf).map_err(|e| FormatError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, FormatError> {
+        ciborium::from_reader(payload).map_err(|e| FormatError(e.to_string()))
+    }
+}
+
+impl PayloadCodec for Json {
+    fn encode_into<T: Serialize + ?Sized>(&self, buf: &mut Vec<u8>, msg: &T) -> Result<(), FormatError> {
+        serde_json::to_writer(buf, msg).map_err(|e| FormatError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, FormatError> {
+        serde_json::from_slice(payload).map_err(|e| FormatError(e.to_string()))
+    }
+}
+
+/// A payload format chosen at runtime, named `msgpack`, `cbor` or `json` on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+    Json,
+}
+
+impl WireFormat {
+    /// The format a request payload is in: JSON if it opens with `{`, CBOR if
+    /// it opens with a CBOR map header (a string in MessagePack, never a request)
+    pub fn detect(payload: &[u8]) -> Self {
+        match payload.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => WireFormat::Json,
+            Some(0xa0..=0xbf) => WireFormat::Cbor,
+            _ => WireFormat::MessagePack,
+        }
+    }
+}
+
+impl PayloadCodec for WireFormat {
+    fn encode_into<T: Serialize + ?Sized>(&self, buf: &mut Vec<u8>, msg: &T) -> Result<(), FormatError> {
+        match self {
+            WireFormat::MessagePack => MessagePack.encode_into(buf, msg),
+            WireFormat::Cbor => Cbor.encode_into(buf, msg),
+            WireFormat::Json => Json.encode_into(buf, msg),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, FormatError> {
+        match self {
+            WireFormat::MessagePack => MessagePack.decode(payload),
+            WireFormat::Cbor => Cbor.decode(payload),
+            WireFormat::Json => Json.decode(payload),
+        }
+    }
+}
+
+impl FromStr for WireFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "msgpack" => Ok(WireFormat::MessagePack),
+            "cbor" => Ok(WireFormat::Cbor),
+            "json" => Ok(WireFormat::Json),
+            other => Err(format!("unknown codec: {other} (expected msgpack, cbor or json)")),
+        }
+    }
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WireFormat::MessagePack => "msgpack",
+            WireFormat::Cbor => "cbor",
+            WireFormat::Json => "json",
+        })
+    }
+}
+
+/// A payload could not be encoded or decoded
+#[derive(Debug, Clone)]
+pub struct FormatError(pub String);
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for FormatError {}
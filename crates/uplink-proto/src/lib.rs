@@ -0,0 +1,454 @@
+//! uplink-proto: framing, common messages and per-service message types ([`pty`])
+//!
+//! Wire format: [1 byte tag][4 byte length BE][payload]
+//!
+//! Payloads are MessagePack unless the client negotiates another
+//! [`WireFormat`] in Hello; see [`format`].
+
+pub mod codec;
+pub mod compress;
+pub mod format;
+pub mod pty;
+pub mod writer;
+
+pub use codec::{FrameCodec, FrameTooLarge, DEFAULT_MAX_FRAME_LEN};
+pub use format::{FormatError, PayloadCodec, WireFormat};
+pub use writer::FrameWriter;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Size of the tag + length header that precedes every payload
+pub const FRAME_HEADER_LEN: usize = 5;
+/// Header size once [`CAP_MULTIPLEX`] is negotiated: tag, service id, length
+pub const MUX_FRAME_HEADER_LEN: usize = 6;
+
+// Service ids carried by multiplexed frames
+pub const SERVICE_PTY: u8 = 1;
+pub const SERVICE_FS: u8 = 2;
+
+// Message type tags - version negotiation, shared by every service
+pub const MSG_HELLO: u8 = 7;
+pub const MSG_HELLO_ACK: u8 = 14;
+
+// Message type tags - responses shared by every service
+pub const MSG_OK: u8 = 11;
+pub const MSG_ERROR: u8 = 12;
+pub const MSG_PROTOCOL_ERROR: u8 = 19;
+
+/// Capability bits whose meaning is shared by every service
+/// The lower 32 bits are each service's own
+pub const SHARED_CAPABILITIES_MASK: u64 = 0xFFFF_FFFF_0000_0000;
+
+/// Shared capability: bulk frames carry a flags byte and may be zstd-compressed
+/// (see [`compress`])
+pub const CAP_COMPRESSION: u64 = 1 << 32;
+
+/// Shared capability: frames carry a service id after the tag,
+/// [1 byte tag][1 byte service][4 byte length BE][payload], so one socket can
+/// carry several services. A client asking for it sends nothing after its
+/// Hello until the HelloAck arrives; if granted, both directions switch from
+/// the next frame on.
+pub const CAP_MULTIPLEX: u64 = 1 << 33;
+
+/// Request: announce the client's protocol version and capabilities
+/// Services extend it with their own fields alongside these
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hello {
+    pub id: u32,
+    pub version: u32,
+    #[serde(default)]
+    pub capabilities: u64,
+}
+
+/// Response: negotiated protocol version and capabilities
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelloAck {
+    pub id: u32,
+    pub version: u32,
+    /// Capabilities supported by both sides
+    pub capabilities: u64,
+    /// "<name> <version>", for logs
+    pub server: String,
+    #[serde(default)]
+    pub build: Option<BuildInfo>,
+}
+
+/// What a server binary is and where it runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub name: String,
+    pub version: String,
+    pub os: String,
+    pub arch: String,
+}
+
+impl BuildInfo {
+    /// Build info for `name` at `version` (usually `env!("CARGO_PKG_VERSION")`) on this platform
+    pub fn new(name: &str, version: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+impl Hello {
+    /// Answer this Hello: the version both sides speak and the capabilities both
+    /// support, or an error if the client is older than `min_version`
+    pub fn accept(&self, build: BuildInfo, min_version: u32, max_version: u32, supported: u64) -> Result<HelloAck, ErrorResponse> {
+        if self.version < min_version {
+            return Err(ErrorResponse::new(
+                self.id,
+                ErrorCode::UnsupportedVersion,
+                format!("unsupported protocol version {} (server supports {}..={})", self.version, min_version, max_version),
+            ));
+        }
+        Ok(HelloAck {
+            id: self.id,
+            version: self.version.min(max_version),
+            capabilities: self.capabilities & supported,
+            server: format!("{} {}", build.name, build.version),
+            build: Some(build),
+        })
+    }
+}
+
+/// Response: request completed successfully
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OkResponse {
+    pub id: u32,
+}
+
+/// Response: request failed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub id: u32,
+    /// An [`ErrorCode`]; 0 from servers that predate codes
+    #[serde(default)]
+    pub code: u16,
+    /// Human-readable detail, not meant to be matched on
+    pub message: String,
+}
+
+impl ErrorResponse {
+    pub fn new(id: u32, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { id, code: code as u16, message: message.into() }
+    }
+
+    /// The error's code, or `None` if it is newer than this build
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        ErrorCode::try_from(self.code).ok()
+    }
+}
+
+/// Why a request failed, sent as [`ErrorResponse::code`]
+/// Values are part of the protocol and never reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// No more specific code applies
+    Unknown = 0,
+    /// The server hit a bug or an unexpected state
+    Internal = 1,
+    /// The client's protocol version is too old
+    UnsupportedVersion = 2,
+    /// The auth token was missing or wrong
+    AuthFailed = 3,
+    /// The terminal, log or file named in the request doesn't exist
+    NotFound = 4,
+    /// A policy hook or the user refused the request
+    Denied = 5,
+    /// The request took longer than its timeout
+    Timeout = 6,
+    /// The feature is turned off on this server or session
+    Disabled = 7,
+    /// An I/O operation failed
+    Io = 8,
+    /// A process could not be started
+    SpawnFailed = 9,
+}
+
+impl TryFrom<u16> for ErrorCode {
+    type Error = u16;
+
+    fn try_from(code: u16) -> Result<Self, u16> {
+        Ok(match code {
+            0 => ErrorCode::Unknown,
+            1 => ErrorCode::Internal,
+            2 => ErrorCode::UnsupportedVersion,
+            3 => ErrorCode::AuthFailed,
+            4 => ErrorCode::NotFound,
+            5 => ErrorCode::Denied,
+            6 => ErrorCode::Timeout,
+            7 => ErrorCode::Disabled,
+            8 => ErrorCode::Io,
+            9 => ErrorCode::SpawnFailed,
+            other => return Err(other),
+        })
+    }
+}
+
+impl From<&std::io::Error> for ErrorCode {
+    fn from(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            std::io::ErrorKind::TimedOut => ErrorCode::Timeout,
+            _ => ErrorCode::Io,
+        }
+    }
+}
+
+/// Response: a frame could not be processed at all; `tag` is the offending
+/// frame's tag, and `id` its request id if one could be read from the payload
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProtocolError {
+    pub tag: u8,
+    #[serde(default)]
+    pub id: Option<u32>,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(tag: u8, id: Option<u32>, message: impl Into<String>) -> Self {
+        Self { tag, id, message: message.into() }
+    }
+}
+
+/// One tagged message as read off the wire
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub tag: u8,
+    /// Which service the frame is for; `None` unless [`CAP_MULTIPLEX`] is in use
+    pub service: Option<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Decode a MessagePack payload
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, FormatError> {
+        self.decode_as(WireFormat::MessagePack)
+    }
+
+    /// Decode a payload in `format`, the one negotiated for the connection
+    pub fn decode_as<T: DeserializeOwned>(&self, format: WireFormat) -> Result<T, FormatError> {
+        format.decode(&self.payload)
+    }
+}
+
+/// Serialize a message into a complete frame: [1 byte tag][4 byte length BE][MessagePack payload]
+pub fn encode_frame<T: Serialize>(tag: u8, msg: &T) -> Result<Vec<u8>, SendError> {
+    encode_frame_as(WireFormat::MessagePack, tag, msg)
+}
+
+/// Encode a message as a complete frame with its payload in `format`
+pub fn encode_frame_as<T: Serialize>(format: WireFormat, tag: u8, msg: &T) -> Result<Vec<u8>, SendError> {
+    let mut frame = vec![tag, 0, 0, 0, 0];
+    format.encode_into(&mut frame, msg).map_err(|e| SendError::Serialize(e.0))?;
+    let len = (frame.len() - FRAME_HEADER_LEN) as u32;
+    frame[1..FRAME_HEADER_LEN].copy_from_slice(&len.to_be_bytes());
+    Ok(frame)
+}
+
+/// Turn an encoded frame (plain or flagged) into a multiplexed one for `service`
+pub fn add_service_id(frame: &mut Vec<u8>, service: u8) {
+    frame.insert(1, service);
+}
+
+/// Read one frame from a blocking stream
+/// Async readers should wrap the stream in a `FramedRead` with [`FrameCodec`]
+pub fn read_frame_blocking<R: std::io::Read + ?Sized>(reader: &mut R) -> std::io::Result<Frame> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Frame { tag: header[0], service: None, payload })
+}
+
+/// Send a tagged MessagePack message through a connection's writer task
+/// Returns a specific error type to allow callers to handle write failures appropriately
+///
+/// Cancelling the caller once the frame is queued can never leave a partial
+/// frame on the wire; see [`FrameWriter`]
+pub async fn send_msg<T: Serialize>(sock: &FrameWriter, tag: u8, msg: &T) -> Result<(), SendError> {
+    send_msg_as(sock, WireFormat::MessagePack, tag, msg).await
+}
+
+/// [`send_msg`] with the payload in `format`
+pub async fn send_msg_as<T: Serialize>(sock: &FrameWriter, format: WireFormat, tag: u8, msg: &T) -> Result<(), SendError> {
+    sock.send_frame(encode_frame_as(format, tag, msg)?).await
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    Serialize(String),
+    Write(String),
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Serialize(e) => write!(f, "serialization failed: {}", e),
+            SendError::Write(e) => write!(f, "socket write failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use std::time::Duration;
+    use tokio::net::UnixStream;
+    use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Blob {
+        data: Vec<u8>,
+    }
+
+    #[tokio::test]
+    async fn cancelled_send_still_writes_whole_frame() {
+        let (server, client) = UnixStream::pair().unwrap();
+        let (_server_read, server_write) = server.into_split();
+        let (client_read, _client_write) = client.into_split();
+        let mut frames = FramedRead::new(client_read, FrameCodec::new());
+        let sock_write = FrameWriter::spawn(server_write);
+
+        // Large enough to overflow the socket buffer, so the write can't finish
+        // before the timeout cancels the sending future
+        let big = Blob { data: vec![0xAB; 4 * 1024 * 1024] };
+        let cancelled = tokio::time::timeout(Duration::from_millis(10), send_msg(&sock_write, 20, &big)).await;
+        assert!(cancelled.is_err(), "send should have been cancelled while blocked");
+
+        let small = OkResponse { id: 7 };
+        let reader = tokio::spawn(async move {
+            let first = frames.next().await.unwrap().unwrap();
+            let second = frames.next().await.unwrap().unwrap();
+            (first, second)
+        });
+        send_msg(&sock_write, MSG_OK, &small).await.unwrap();
+
+        let (first, second) = reader.await.unwrap();
+        assert_eq!(first.tag, 20);
+        let blob: Blob = first.decode().unwrap();
+        assert_eq!(blob.data.len(), big.data.len());
+        assert_eq!(second.tag, MSG_OK);
+        let ok: OkResponse = second.decode().unwrap();
+        assert_eq!(ok.id, 7);
+    }
+
+    #[test]
+    fn codec_waits_for_whole_frames() {
+        let mut codec = FrameCodec::new();
+        let mut wire = bytes::BytesMut::new();
+        wire.extend_from_slice(&encode_frame(MSG_ERROR, &ErrorResponse::new(3, ErrorCode::NotFound, "nope")).unwrap());
+        wire.extend_from_slice(&encode_frame(MSG_OK, &OkResponse { id: 4 }).unwrap());
+        let mut second_half = wire.split_off(wire.len() - 2);
+
+        let first = codec.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(first.tag, MSG_ERROR);
+        let error: ErrorResponse = first.decode().unwrap();
+        assert_eq!((error.error_code(), error.message.as_str()), (Some(ErrorCode::NotFound), "nope"));
+        assert!(codec.decode(&mut wire).unwrap().is_none(), "partial frame must not decode");
+
+        wire.unsplit(second_half.split());
+        let second = codec.decode(&mut wire).unwrap().unwrap();
+        assert_eq!(second.tag, MSG_OK);
+        assert_eq!(second.decode::<OkResponse>().unwrap().id, 4);
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn codec_switches_to_multiplexed_frames_mid_stream() {
+        let mut codec = FrameCodec::new();
+        let mut wire = bytes::BytesMut::new();
+        wire.extend_from_slice(&encode_frame(MSG_HELLO, &Hello { id: 1, version: 1, capabilities: CAP_MULTIPLEX }).unwrap());
+        let mut ok = encode_frame(MSG_OK, &OkResponse { id: 2 }).unwrap();
+        add_service_id(&mut ok, SERVICE_FS);
+        wire.extend_from_slice(&ok);
+
+        let hello = codec.decode(&mut wire).unwrap().unwrap();
+        assert_eq!((hello.tag, hello.service), (MSG_HELLO, None));
+        codec.set_multiplexed(true);
+        let frame = codec.decode(&mut wire).unwrap().unwrap();
+        assert_eq!((frame.tag, frame.service), (MSG_OK, Some(SERVICE_FS)));
+        assert_eq!(frame.decode::<OkResponse>().unwrap().id, 2);
+
+        let mut out = bytes::BytesMut::new();
+        codec.encode(frame, &mut out).unwrap();
+        assert_eq!(&out[..], &ok[..]);
+    }
+
+    #[test]
+    fn codec_rejects_oversized_frames_from_the_header() {
+        let mut codec = FrameCodec::with_max_frame_len(16);
+        let mut wire = bytes::BytesMut::from(&[MSG_OK, 0xFF, 0xFF, 0xFF, 0xFF][..]);
+        let err = codec.decode(&mut wire).unwrap_err();
+        let too_large = FrameTooLarge::from_io(&err).expect("limit error");
+        assert_eq!((too_large.tag, too_large.len, too_large.max), (MSG_OK, u32::MAX as usize, 16));
+        assert!(wire.capacity() < 1024, "nothing should be reserved for the payload");
+    }
+
+    #[test]
+    fn hello_negotiates_down_to_common_ground() {
+        let build = BuildInfo::new("test", "1.0.0");
+        let newer = Hello { id: 1, version: 9, capabilities: 0b1111 };
+        let ack = newer.accept(build.clone(), 2, 3, 0b0101).unwrap();
+        assert_eq!((ack.version, ack.capabilities), (3, 0b0101));
+        assert_eq!(ack.server, "test 1.0.0");
+
+        let older = Hello { id: 2, version: 1, capabilities: 0 };
+        let err = older.accept(build, 2, 3, 0).unwrap_err();
+        assert_eq!((err.id, err.error_code()), (2, Some(ErrorCode::UnsupportedVersion)));
+    }
+
+    #[test]
+    fn payload_format_is_detected_from_the_first_byte() {
+        let ok = OkResponse { id: 9 };
+        for format in [WireFormat::MessagePack, WireFormat::Cbor, WireFormat::Json] {
+            let payload = format.encode(&ok).unwrap();
+            assert_eq!(WireFormat::detect(&payload), format);
+            let decoded: OkResponse = WireFormat::detect(&payload).decode(&payload).unwrap();
+            assert_eq!(decoded.id, 9);
+        }
+        assert_eq!("json".parse::<WireFormat>().unwrap(), WireFormat::Json);
+        assert!("yaml".parse::<WireFormat>().is_err());
+    }
+
+    #[test]
+    fn flagged_frames_compress_only_past_the_threshold() {
+        let mut codec = FrameCodec::new();
+        for (size, compressed) in [(16, false), (4096, true)] {
+            let blob = Blob { data: vec![b'x'; size] };
+            let mut wire = bytes::BytesMut::from(
+                &compress::encode_flagged_frame(WireFormat::MessagePack, 20, &blob, 512).unwrap()[..],
+            );
+            let frame = codec.decode(&mut wire).unwrap().unwrap();
+            assert_eq!(frame.payload[0] & compress::FLAG_ZSTD != 0, compressed);
+            let payload = compress::unflag_payload(&frame.payload).unwrap();
+            let decoded: Blob = rmp_serde::from_slice(&payload).unwrap();
+            assert_eq!(decoded.data.len(), size);
+        }
+    }
+
+    #[test]
+    fn error_code_survives_older_and_newer_peers() {
+        #[derive(Serialize)]
+        struct LegacyError {
+            id: u32,
+            message: String,
+        }
+        let legacy = rmp_serde::to_vec_named(&LegacyError { id: 1, message: "old".into() }).unwrap();
+        let decoded: ErrorResponse = rmp_serde::from_slice(&legacy).unwrap();
+        assert_eq!(decoded.error_code(), Some(ErrorCode::Unknown));
+
+        let future = ErrorResponse { id: 2, code: 999, message: "new".into() };
+        assert_eq!(future.error_code(), None);
+    }
+}
@@ -0,0 +1,446 @@
+//! Protocol message types for uplink-pty
+//!
+//! They live here rather than in uplink-pty so clients can use them without
+//! depending on the server.
+//!
+//! Wire format: [1 byte tag][4 byte length BE][payload]
+//!
+//! Every response carries the `id` of the request it answers, and responses
+//! may arrive in any order. GET_ENV, LIST_LOGS, READ_LOG and COMPLETE run
+//! concurrently and are answered as they finish, as is a CREATE waiting on the
+//! client's confirmation. Every other request is handled to completion before
+//! the next frame is read, so requests that change a terminal (create, input,
+//! resize, kill) take effect in the order they were sent. Events and
+//! heartbeats carry no id; a protocol error carries one when the rejected
+//! frame's payload had one.
+//!
+//! Payloads are MessagePack unless the client asks for CBOR or JSON with
+//! `codec` in Hello and the server offers it (`--codec cbor`, `--codec json`).
+//! Auth and Hello may be sent in any offered format; every later request must
+//! use the negotiated one, and a payload in another format gets a protocol
+//! error.
+//!
+//! With CAP_MULTIPLEX, frames carry a service id after the tag (see
+//! [`crate::CAP_MULTIPLEX`]); this server answers for SERVICE_PTY only.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub use crate::{
+    BuildInfo, ErrorCode, ErrorResponse, OkResponse, WireFormat, CAP_COMPRESSION, CAP_MULTIPLEX, ProtocolError, MSG_ERROR, MSG_HELLO, MSG_HELLO_ACK, MSG_OK, MSG_PROTOCOL_ERROR,
+    SERVICE_PTY,
+};
+
+/// Protocol version spoken by this server
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest client protocol version still accepted
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Capability bits announced in Hello / HelloAck
+pub const CAP_CONFIRM: u64 = 1 << 0;
+pub const CAP_EXIT_SIGNAL: u64 = 1 << 1;
+pub const CAP_HEARTBEAT: u64 = 1 << 2;
+pub const CAP_CANCEL: u64 = 1 << 3;
+pub const CAP_RESTART: u64 = 1 << 4;
+pub const CAP_COMPLETE: u64 = 1 << 5;
+pub const CAP_OUTPUT_LOG: u64 = 1 << 6;
+
+/// Capabilities this server supports
+pub const SERVER_CAPABILITIES: u64 = CAP_CONFIRM
+    | CAP_EXIT_SIGNAL
+    | CAP_HEARTBEAT
+    | CAP_CANCEL
+    | CAP_RESTART
+    | CAP_COMPLETE
+    | CAP_OUTPUT_LOG
+    | CAP_COMPRESSION
+    | CAP_MULTIPLEX;
+
+// Message type tags - requests (client to server)
+pub const MSG_CREATE: u8 = 1;
+pub const MSG_INPUT: u8 = 2;
+pub const MSG_RESIZE: u8 = 3;
+pub const MSG_KILL: u8 = 4;
+pub const MSG_CONFIRM_REPLY: u8 = 5;
+pub const MSG_CANCEL: u8 = 8;
+pub const MSG_GET_ENV: u8 = 9;
+
+// Message type tags - requests (client to server, continued)
+pub const MSG_COMPLETE: u8 = 40;
+pub const MSG_LIST_LOGS: u8 = 41;
+pub const MSG_READ_LOG: u8 = 42;
+pub const MSG_AUTH: u8 = 43;
+pub const MSG_BATCH: u8 = 44;
+pub const MSG_SERVER_INFO: u8 = 45;
+
+// Message type tags - heartbeat (either direction)
+pub const MSG_PING: u8 = 6;
+pub const MSG_PONG: u8 = 13;
+
+// Message type tags - responses (server to client)
+pub const MSG_CREATED: u8 = 10;
+pub const MSG_ENV: u8 = 15;
+pub const MSG_COMPLETIONS: u8 = 16;
+pub const MSG_LOGS: u8 = 17;
+pub const MSG_LOG_DATA: u8 = 18;
+
+// Message type tags - responses (server to client, continued)
+pub const MSG_BATCH_RESULT: u8 = 50;
+pub const MSG_SERVER_INFO_RESULT: u8 = 51;
+
+// Message type tags - events (server to client)
+pub const MSG_DATA: u8 = 20;
+pub const MSG_EXIT: u8 = 21;
+pub const MSG_RESTARTED: u8 = 22;
+pub const MSG_LIFECYCLE: u8 = 23;
+
+// Message type tags - prompts (server to client, answered by the client)
+pub const MSG_CONFIRM: u8 = 30;
+
+/// Name of a request tag, for logs
+pub fn tag_name(tag: u8) -> &'static str {
+    match tag {
+        MSG_CREATE => "create",
+        MSG_INPUT => "input",
+        MSG_RESIZE => "resize",
+        MSG_KILL => "kill",
+        MSG_CONFIRM_REPLY => "confirm_reply",
+        MSG_PING => "ping",
+        MSG_HELLO => "hello",
+        MSG_CANCEL => "cancel",
+        MSG_GET_ENV => "get_env",
+        MSG_PONG => "pong",
+        MSG_COMPLETE => "complete",
+        MSG_LIST_LOGS => "list_logs",
+        MSG_READ_LOG => "read_log",
+        MSG_AUTH => "auth",
+        MSG_BATCH => "batch",
+        MSG_SERVER_INFO => "server_info",
+        _ => "unknown",
+    }
+}
+
+/// Request: present the shared secret
+/// Required first when the server has a token; anything else before it drops the connection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub id: u32,
+    pub token: String,
+}
+
+/// Request: announce the client's protocol version and capabilities
+/// Optional; clients that skip it get version 1 with no optional capabilities
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloRequest {
+    #[serde(flatten)]
+    pub hello: crate::Hello,
+    /// Ask for minimal mode: small output buffers, no output logs or completion
+    /// helpers, for memory-constrained hosts
+    #[serde(default)]
+    pub minimal: bool,
+    /// Payload format both sides use from the HelloAck on
+    #[serde(default)]
+    pub codec: Option<WireFormat>,
+}
+
+/// Request to create a new terminal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateRequest {
+    pub id: u32,
+    pub shell: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub cwd: String,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    pub cols: u16,
+    pub rows: u16,
+    /// Relaunch the shell under the same terminal_id whenever it exits
+    #[serde(default)]
+    pub restart_on_exit: bool,
+    /// Output chunks buffered for this terminal before the overflow policy applies
+    #[serde(default)]
+    pub output_buffer: Option<usize>,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+/// What a terminal does when its output buffer is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Stop reading from the PTY until the client catches up
+    #[default]
+    Block,
+    /// Discard new output while the buffer is full
+    Drop,
+}
+
+/// Request to send input to a terminal
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InputRequest {
+    pub id: u32,
+    pub terminal_id: u32,
+    pub data: Vec<u8>,
+    /// Override the server's request timeout, in milliseconds
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Request to resize a terminal
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResizeRequest {
+    pub id: u32,
+    pub terminal_id: u32,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Request to kill a terminal
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KillRequest {
+    pub id: u32,
+    pub terminal_id: u32,
+}
+
+/// Request a terminal process's live environment
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetEnvRequest {
+    pub id: u32,
+    pub terminal_id: u32,
+}
+
+/// Request completion candidates for a partial command line
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompleteRequest {
+    pub id: u32,
+    pub line: String,
+    pub cwd: String,
+}
+
+/// Request the stored terminal output logs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListLogsRequest {
+    pub id: u32,
+    /// Override the server's request timeout, in milliseconds
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Request the contents of a terminal output log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadLogRequest {
+    pub id: u32,
+    pub name: String,
+    /// Only return this many bytes from the end of the log
+    #[serde(default)]
+    pub tail: Option<u64>,
+    /// Override the server's request timeout, in milliseconds
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Request to abandon an in-flight request
+/// No response is sent for the cancelled id (or for the cancel itself)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelRequest {
+    pub id: u32,
+}
+
+/// Request: describe the server process
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerInfoRequest {
+    pub id: u32,
+}
+
+/// Response: terminal created successfully
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedResponse {
+    pub id: u32,
+    pub terminal_id: u32,
+    pub pid: u32,
+    /// Output log name, when the server keeps output logs
+    #[serde(default)]
+    pub log: Option<String>,
+}
+
+/// Response: negotiated protocol version and capabilities
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloAck {
+    #[serde(flatten)]
+    pub ack: crate::HelloAck,
+    /// Whether minimal mode is in effect
+    #[serde(default)]
+    pub minimal: bool,
+    /// In minimal mode, the most terminal output buffered per terminal, in bytes
+    #[serde(default)]
+    pub memory_budget: Option<u64>,
+    /// Payload format the server now sends and expects
+    #[serde(default)]
+    pub codec: WireFormat,
+}
+
+/// Response: environment of a terminal's process
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnvResponse {
+    pub id: u32,
+    pub env: HashMap<String, String>,
+}
+
+/// Response: completion candidates, sorted and deduplicated
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompletionsResponse {
+    pub id: u32,
+    pub candidates: Vec<String>,
+}
+
+/// A stored log as reported to clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogInfo {
+    pub name: String,
+    /// Bytes across all retained files
+    pub size: u64,
+    /// Last write, in seconds since the Unix epoch
+    pub modified: u64,
+}
+
+/// Response: stored output logs, most recently written first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogsResponse {
+    pub id: u32,
+    pub logs: Vec<LogInfo>,
+}
+
+/// Response: contents of an output log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogDataResponse {
+    pub id: u32,
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// One message inside a batch: its tag and MessagePack payload, as it would be framed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchItem {
+    pub tag: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Request: run several requests and answer them all in one BatchResult
+/// Only input, resize, kill, get-env and log requests may be batched
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchRequest {
+    pub id: u32,
+    pub requests: Vec<BatchItem>,
+    /// Run the requests concurrently instead of in order
+    #[serde(default)]
+    pub parallel: bool,
+}
+
+/// Response: every response the batched requests produced, in request order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub id: u32,
+    pub responses: Vec<BatchItem>,
+}
+
+/// Response: what the server is and how busy it is
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerInfoResponse {
+    pub id: u32,
+    #[serde(flatten)]
+    pub build: BuildInfo,
+    /// Commit the binary was built from, when built from a git checkout
+    #[serde(default)]
+    pub git_hash: Option<String>,
+    pub pid: u32,
+    pub uptime_ms: u64,
+    /// Clients connected to this process, over every listener
+    pub connections: u32,
+    /// Terminals alive in this process, across all connections
+    pub terminals: u32,
+    /// Resident memory in bytes, where the platform reports it
+    #[serde(default)]
+    pub memory_rss: Option<u64>,
+}
+
+/// Event: terminal output data
+/// With CAP_COMPRESSION the payload is flagged and may be zstd-compressed
+/// (see [`crate::compress`])
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataEvent {
+    pub terminal_id: u32,
+    pub data: Vec<u8>,
+}
+
+/// Event: terminal process exited
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExitEvent {
+    pub terminal_id: u32,
+    pub code: Option<i32>,
+    /// Signal number when the process was terminated by a signal
+    #[serde(default)]
+    pub signal: Option<i32>,
+}
+
+/// Event: a restart_on_exit terminal's shell exited and was relaunched
+/// `code`/`signal` describe how the previous process ended
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestartedEvent {
+    pub terminal_id: u32,
+    pub pid: u32,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+/// Event: the server is shutting down
+/// Sent unprompted; clients that don't know the tag can ignore it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LifecycleEvent {
+    pub stage: LifecycleStage,
+    /// Terminals still running at this stage
+    pub terminals: u32,
+}
+
+/// Stages of a server shutdown, in the order they're reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleStage {
+    /// Terminals have been hung up and are being waited on
+    Draining,
+    /// Terminals that ignored the hangup were killed
+    ForceKilled,
+    /// All terminals are gone; the connection is about to close
+    Stopped,
+}
+
+/// Prompt: ask the client to confirm spawning a terminal
+/// `id` is the id of the pending CreateRequest
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmPrompt {
+    pub id: u32,
+    pub shell: String,
+    pub cwd: String,
+    pub reason: String,
+}
+
+/// Reply to a ConfirmPrompt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfirmReply {
+    pub id: u32,
+    pub allow: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Heartbeat: ping, answered with a Pong carrying the same seq
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ping {
+    pub seq: u32,
+}
+
+/// Heartbeat: reply to a Ping
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pong {
+    pub seq: u32,
+}
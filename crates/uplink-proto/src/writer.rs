@@ -0,0 +1,73 @@
+//! One writer task per connection
+//!
+//! [`FrameWriter`] hands encoded frames to a task that owns the write half, so
+//! frames go out whole and in the order they were queued. A caller that is
+//! cancelled after queueing (e.g. a select! branch losing during shutdown)
+//! can't leave a partial frame on the wire; the frame is still written.
+
+use crate::SendError;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+/// A frame waiting for the writer task
+struct Queued {
+    frame: Vec<u8>,
+    done: oneshot::Sender<std::io::Result<()>>,
+}
+
+/// Cloneable handle to a connection's writer task
+/// The task ends, closing the write half, once every handle is dropped
+#[derive(Clone)]
+pub struct FrameWriter {
+    queue: mpsc::UnboundedSender<Queued>,
+}
+
+impl FrameWriter {
+    /// Start the writer task for `sock`
+    pub fn spawn<W>(sock: W) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (queue, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_queued(sock, rx));
+        Self { queue }
+    }
+
+    /// Write an encoded frame, waiting until it is on the socket
+    /// Each caller has at most one frame queued, which keeps the queue bounded
+    pub async fn send_frame(&self, frame: Vec<u8>) -> Result<(), SendError> {
+        self.queue_frame(frame)?.await
+    }
+
+    /// Queue a frame without waiting for it to be written
+    /// The frame is written even if the returned future is dropped
+    pub fn queue_frame(&self, frame: Vec<u8>) -> Result<impl Future<Output = Result<(), SendError>> + use<>, SendError> {
+        let (done, written) = oneshot::channel();
+        self.queue.send(Queued { frame, done }).map_err(|_| closed())?;
+        Ok(async move {
+            match written.await {
+                Ok(result) => result.map_err(|e| SendError::Write(e.to_string())),
+                Err(_) => Err(closed()),
+            }
+        })
+    }
+}
+
+fn closed() -> SendError {
+    SendError::Write("connection closed".into())
+}
+
+async fn write_queued<W>(mut sock: W, mut rx: mpsc::UnboundedReceiver<Queued>)
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    while let Some(Queued { frame, done }) = rx.recv().await {
+        let result = sock.write_all(&frame).await;
+        let failed = result.is_err();
+        let _ = done.send(result);
+        if failed {
+            // Later frames would land after a torn one; fail them instead
+            break;
+        }
+    }
+}
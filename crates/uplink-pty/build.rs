@@ -0,0 +1,24 @@
+//! Record the git commit the server is built from, when there is one
+//!
+//! Builds outside a checkout (e.g. the Docker image) can pass it in
+//! `UPLINK_GIT_HASH` instead.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=UPLINK_GIT_HASH");
+    if std::env::var_os("UPLINK_GIT_HASH").is_some() {
+        return;
+    }
+    println!("cargo:rerun-if-changed=../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../.git/refs/heads");
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=UPLINK_GIT_HASH={}", hash.trim());
+    }
+}
@@ -1,13 +1,13 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::{self, BufRead, Read, Write};
+use std::io::{self, BufRead, Write};
 use std::os::unix::net::UnixStream;
+use uplink_proto::{ErrorResponse, OkResponse, MSG_ERROR, MSG_OK};
 
 const MSG_CREATE: u8 = 1;
 const MSG_INPUT: u8 = 2;
+const MSG_AUTH: u8 = 43;
 const MSG_CREATED: u8 = 10;
-const MSG_OK: u8 = 11;
-const MSG_ERROR: u8 = 12;
 const MSG_DATA: u8 = 20;
 const MSG_EXIT: u8 = 21;
 
@@ -22,6 +22,12 @@ struct CreateRequest {
     rows: u16,
 }
 
+#[derive(Debug, Serialize)]
+struct AuthRequest {
+    id: u32,
+    token: String,
+}
+
 #[derive(Debug, Serialize)]
 struct InputRequest {
     id: u32,
@@ -36,17 +42,6 @@ struct CreatedResponse {
     pid: u32,
 }
 
-#[derive(Debug, Deserialize)]
-struct OkResponse {
-    id: u32,
-}
-
-#[derive(Debug, Deserialize)]
-struct ErrorResponse {
-    id: u32,
-    message: String,
-}
-
 #[derive(Debug, Deserialize)]
 struct DataEvent {
     terminal_id: u32,
@@ -57,11 +52,23 @@ struct DataEvent {
 struct ExitEvent {
     terminal_id: u32,
     code: Option<i32>,
+    #[serde(default)]
+    signal: Option<i32>,
 }
 
 fn main() -> io::Result<()> {
     let mut stream = UnixStream::connect("/tmp/uplink-pty.sock")?;
 
+    if let Ok(token) = std::env::var("UPLINK_PTY_TOKEN") {
+        send_msg(&mut stream, MSG_AUTH, &AuthRequest { id: 0, token })?;
+        let (tag, data) = read_msg(&mut stream)?;
+        if tag != MSG_OK {
+            let resp: ErrorResponse = rmp_serde::from_slice(&data).unwrap();
+            eprintln!("Authentication failed: {}", resp.message);
+            return Ok(());
+        }
+    }
+
     let req = CreateRequest {
         id: 1,
         shell: "/bin/bash".into(),
@@ -79,12 +86,12 @@ fn main() -> io::Result<()> {
     let terminal_id = match tag {
         MSG_CREATED => {
             let resp: CreatedResponse = rmp_serde::from_slice(&data).unwrap();
-            println!("Created terminal {} with pid {}", resp.terminal_id, resp.pid);
+            println!("Created terminal {} with pid {} (request {})", resp.terminal_id, resp.pid, resp.id);
             resp.terminal_id
         }
         MSG_ERROR => {
             let resp: ErrorResponse = rmp_serde::from_slice(&data).unwrap();
-            eprintln!("Error: {}", resp.message);
+            eprintln!("Error (request {}): {}", resp.id, resp.message);
             return Ok(());
         }
         _ => {
@@ -97,14 +104,11 @@ fn main() -> io::Result<()> {
 
     // Read initial shell output (prompt)
     std::thread::sleep(std::time::Duration::from_millis(200));
-    loop {
-        match read_msg(&mut stream) {
-            Ok((MSG_DATA, data)) => {
-                let event: DataEvent = rmp_serde::from_slice(&data).unwrap();
-                print!("{}", String::from_utf8_lossy(&event.data));
-                io::stdout().flush()?;
-            }
-            _ => break,
+    while let Ok((MSG_DATA, data)) = read_msg(&mut stream) {
+        let event: DataEvent = rmp_serde::from_slice(&data).unwrap();
+        if event.terminal_id == terminal_id {
+            print!("{}", String::from_utf8_lossy(&event.data));
+            io::stdout().flush()?;
         }
     }
 
@@ -119,7 +123,11 @@ fn main() -> io::Result<()> {
         };
         stream.set_nonblocking(false)?;
         send_msg(&mut stream, MSG_INPUT, &req)?;
-        let _ = read_msg(&mut stream)?;
+        let (tag, data) = read_msg(&mut stream)?;
+        if tag == MSG_OK {
+            let resp: OkResponse = rmp_serde::from_slice(&data).unwrap();
+            debug_assert_eq!(resp.id, req.id);
+        }
 
         stream.set_nonblocking(true)?;
         std::thread::sleep(std::time::Duration::from_millis(100));
@@ -128,12 +136,17 @@ fn main() -> io::Result<()> {
             match read_msg(&mut stream) {
                 Ok((MSG_DATA, data)) => {
                     let event: DataEvent = rmp_serde::from_slice(&data).unwrap();
-                    print!("{}", String::from_utf8_lossy(&event.data));
-                    io::stdout().flush()?;
+                    if event.terminal_id == terminal_id {
+                        print!("{}", String::from_utf8_lossy(&event.data));
+                        io::stdout().flush()?;
+                    }
                 }
                 Ok((MSG_EXIT, data)) => {
                     let event: ExitEvent = rmp_serde::from_slice(&data).unwrap();
-                    println!("\nTerminal exited with code {:?}", event.code);
+                    match event.signal {
+                        Some(signal) => println!("\nTerminal {} terminated by signal {}", event.terminal_id, signal),
+                        None => println!("\nTerminal {} exited with code {:?}", event.terminal_id, event.code),
+                    }
                     return Ok(());
                 }
                 _ => break,
@@ -145,23 +158,11 @@ fn main() -> io::Result<()> {
 }
 
 fn send_msg<T: Serialize>(stream: &mut UnixStream, tag: u8, msg: &T) -> io::Result<()> {
-    let data = rmp_serde::to_vec(msg).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    stream.write_all(&[tag])?;
-    stream.write_all(&(data.len() as u32).to_be_bytes())?;
-    stream.write_all(&data)?;
-    Ok(())
+    let frame = uplink_proto::encode_frame(tag, msg).map_err(io::Error::other)?;
+    stream.write_all(&frame)
 }
 
 fn read_msg(stream: &mut UnixStream) -> io::Result<(u8, Vec<u8>)> {
-    let mut tag = [0u8; 1];
-    stream.read_exact(&mut tag)?;
-
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf)?;
-    let len = u32::from_be_bytes(len_buf) as usize;
-
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf)?;
-
-    Ok((tag[0], buf))
+    let frame = uplink_proto::read_frame_blocking(stream)?;
+    Ok((frame.tag, frame.payload))
 }
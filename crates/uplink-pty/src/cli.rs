@@ -0,0 +1,302 @@
+//! Command line for the pty service
+//!
+//! Shared by the `uplink-pty` binary and the multi-call `uplink` binary, so
+//! both accept the same options.
+
+use crate::heartbeat::{HeartbeatConfig, TimeoutAction};
+use crate::hooks::{TrustPolicy, UntrustedAction};
+use crate::output_log::OutputLogConfig;
+use crate::protocol::WireFormat;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Log to `file_name` in /tmp and to stderr; keep the guard until exit so the
+/// file gets flushed
+pub fn init_logging(file_name: &str) -> WorkerGuard {
+    let file_appender = rolling::never(Path::new("/tmp"), file_name);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug")))
+        .with(fmt::layer().with_writer(non_blocking).with_ansi(false))
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .init();
+    guard
+}
+
+/// Serve until SIGTERM or SIGINT, with the gateways `args` asks for
+pub async fn run(args: Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = crate::Config::default();
+    if !args.trust_policy.untrusted_roots.is_empty() || args.trust_policy.confirm_all {
+        config.spawn_hooks.push(Arc::new(args.trust_policy));
+    }
+    config.heartbeat = args.heartbeat;
+    config.output_logs = args.output_logs;
+    config.allowed_uids = args.allowed_uids;
+    config.auth_token = args.auth_token;
+    config.max_frame_len = args.max_frame_len;
+    config.request_timeout = args.request_timeout;
+    config.max_connections = args.max_connections;
+    config.codecs = args.codecs;
+    config.websocket_origins = args.websocket_origins;
+
+    let _registration = SidecarRegistration::new();
+    let server = crate::PtyServer::new(config);
+    let signal_server = server.clone();
+    tokio::spawn(async move {
+        match shutdown_signal().await {
+            Ok(name) => {
+                info!(signal = name, "Shutting down");
+                signal_server.shutdown();
+            }
+            Err(e) => error!(error = %e, "Failed to install signal handlers"),
+        }
+    });
+
+    if let Some(addr) = args.websocket {
+        let ws_server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = ws_server.run_websocket(&addr).await {
+                error!(error = %e, "WebSocket gateway failed");
+            }
+        });
+    }
+
+    if let Some(addr) = args.metrics {
+        let metrics_server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics_server.run_metrics(&addr).await {
+                error!(error = %e, "Metrics listener failed");
+            }
+        });
+    }
+
+    server.run(args.endpoint).await
+}
+
+/// This process's pid file in the launcher's sidecar directory, so the
+/// launcher drains it first at shutdown; removed again on drop
+struct SidecarRegistration(PathBuf);
+
+impl SidecarRegistration {
+    /// `None` when not started under the launcher
+    fn new() -> Option<Self> {
+        let dir = std::env::var_os(crate::SIDECAR_DIR_ENV)?;
+        let path = Path::new(&dir).join(std::process::id().to_string());
+        match std::fs::write(&path, b"") {
+            Ok(()) => Some(Self(path)),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Failed to register with the launcher");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for SidecarRegistration {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Wait for SIGTERM or SIGINT, returning which one arrived
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = int.recv() => "SIGINT",
+    })
+}
+
+/// Command-line options for the pty service
+pub struct Args {
+    endpoint: crate::Endpoint,
+    trust_policy: TrustPolicy,
+    heartbeat: Option<HeartbeatConfig>,
+    output_logs: Option<OutputLogConfig>,
+    allowed_uids: Vec<u32>,
+    auth_token: Option<String>,
+    max_frame_len: Option<usize>,
+    request_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    /// Payload formats clients may negotiate besides MessagePack (`--codec`, repeatable)
+    codecs: Vec<WireFormat>,
+    /// Address for the WebSocket gateway, if enabled
+    websocket: Option<String>,
+    /// Browser origins allowed to use the WebSocket gateway
+    websocket_origins: Vec<String>,
+    /// Address for the Prometheus metrics listener, if enabled
+    metrics: Option<String>,
+}
+
+impl Args {
+    /// Parse options, not including the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut endpoint: Option<crate::Endpoint> = None;
+        let mut trust_policy = TrustPolicy::default();
+        // Heartbeats are on by default so dead clients don't keep terminals alive;
+        // only clients that answer pings can time out, so old clients are unaffected
+        let mut heartbeat = Some(HeartbeatConfig::default());
+        let mut output_logs: Option<OutputLogConfig> = None;
+        let mut websocket: Option<String> = None;
+        let mut websocket_origins = Vec::new();
+        let mut metrics: Option<String> = None;
+        let mut allowed_uids = Vec::new();
+        let mut max_frame_len: Option<usize> = None;
+        let mut request_timeout: Option<Duration> = None;
+        let mut max_connections: Option<usize> = None;
+        let mut codecs = Vec::new();
+        let mut auth_token = std::env::var(crate::AUTH_TOKEN_ENV).ok().filter(|t| !t.is_empty());
+
+        let mut iter = args.into_iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--untrusted-root" => {
+                    let root = PathBuf::from(next_value(&mut iter, "--untrusted-root")?);
+                    let root = root.canonicalize().unwrap_or(root);
+                    trust_policy.untrusted_roots.push(root);
+                }
+                "--untrusted-action" => {
+                    trust_policy.action = match next_value(&mut iter, "--untrusted-action")?.as_str() {
+                        "deny" => UntrustedAction::Deny,
+                        "confirm" => UntrustedAction::Confirm,
+                        other => return Err(format!("invalid --untrusted-action: {other}")),
+                    };
+                }
+                "--heartbeat-interval" => {
+                    let secs = parse_secs(&next_value(&mut iter, "--heartbeat-interval")?, "--heartbeat-interval")?;
+                    heartbeat.get_or_insert_with(HeartbeatConfig::default).interval = secs;
+                }
+                "--no-heartbeat" => {
+                    heartbeat = None;
+                }
+                "--heartbeat-timeout" => {
+                    let secs = parse_secs(&next_value(&mut iter, "--heartbeat-timeout")?, "--heartbeat-timeout")?;
+                    heartbeat.get_or_insert_with(HeartbeatConfig::default).timeout = secs;
+                }
+                "--heartbeat-action" => {
+                    let action = match next_value(&mut iter, "--heartbeat-action")?.as_str() {
+                        "kill" => TimeoutAction::Kill,
+                        "detach" => TimeoutAction::Detach,
+                        other => return Err(format!("invalid --heartbeat-action: {other}")),
+                    };
+                    heartbeat.get_or_insert_with(HeartbeatConfig::default).on_timeout = action;
+                }
+                "--output-log-dir" => {
+                    let dir = PathBuf::from(next_value(&mut iter, "--output-log-dir")?);
+                    output_logs.get_or_insert_with(OutputLogConfig::default).dir = dir;
+                }
+                "--output-log-max-bytes" => {
+                    let value = next_value(&mut iter, "--output-log-max-bytes")?;
+                    let bytes = parse_count(&value, "--output-log-max-bytes")?;
+                    output_logs.get_or_insert_with(OutputLogConfig::default).max_file_bytes = bytes;
+                }
+                "--output-log-max-files" => {
+                    let value = next_value(&mut iter, "--output-log-max-files")?;
+                    let files = parse_count(&value, "--output-log-max-files")?;
+                    output_logs.get_or_insert_with(OutputLogConfig::default).max_files = files as usize;
+                }
+                "--output-log-retention" => {
+                    let secs = parse_secs(&next_value(&mut iter, "--output-log-retention")?, "--output-log-retention")?;
+                    output_logs.get_or_insert_with(OutputLogConfig::default).retention = secs;
+                }
+                "--websocket" => {
+                    websocket = Some(next_value(&mut iter, "--websocket")?);
+                }
+                "--websocket-origin" => {
+                    websocket_origins.push(next_value(&mut iter, "--websocket-origin")?);
+                }
+                "--metrics" => {
+                    metrics = Some(next_value(&mut iter, "--metrics")?);
+                }
+                "--socket" => {
+                    endpoint = Some(crate::Endpoint::parse(&next_value(&mut iter, "--socket")?)?);
+                }
+                "--allow-uid" => {
+                    let value = next_value(&mut iter, "--allow-uid")?;
+                    let uid = value.parse().map_err(|_| format!("invalid value for --allow-uid: {value}"))?;
+                    allowed_uids.push(uid);
+                }
+                "--auth-token-file" => {
+                    let path = next_value(&mut iter, "--auth-token-file")?;
+                    let token = std::fs::read_to_string(&path).map_err(|e| format!("failed to read {path}: {e}"))?;
+                    let token = token.trim();
+                    if token.is_empty() {
+                        return Err(format!("auth token file {path} is empty"));
+                    }
+                    auth_token = Some(token.to_string());
+                }
+                "--max-frame-size" => {
+                    let value = next_value(&mut iter, "--max-frame-size")?;
+                    max_frame_len = Some(parse_count(&value, "--max-frame-size")? as usize);
+                }
+                "--request-timeout" => {
+                    request_timeout = Some(parse_secs(&next_value(&mut iter, "--request-timeout")?, "--request-timeout")?);
+                }
+                "--max-connections" => {
+                    let value = next_value(&mut iter, "--max-connections")?;
+                    max_connections = Some(parse_count(&value, "--max-connections")? as usize);
+                }
+                "--codec" => {
+                    codecs.push(next_value(&mut iter, "--codec")?.parse::<WireFormat>()?);
+                }
+                "--confirm-spawn" => {
+                    trust_policy.confirm_all = true;
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(format!("unknown argument: {arg}"));
+                }
+                _ => {
+                    endpoint = Some(crate::Endpoint::parse(&arg)?);
+                }
+            }
+        }
+
+        if websocket.is_some() && auth_token.is_none() {
+            return Err(format!("--websocket requires an auth token (--auth-token-file or {})", crate::AUTH_TOKEN_ENV));
+        }
+
+        Ok(Self {
+            endpoint: endpoint
+                .or_else(crate::Endpoint::from_systemd)
+                .unwrap_or_else(|| crate::Endpoint::Path(PathBuf::from("/tmp/uplink-pty.sock"))),
+            trust_policy,
+            heartbeat,
+            output_logs,
+            websocket,
+            websocket_origins,
+            metrics,
+            allowed_uids,
+            auth_token,
+            max_frame_len,
+            request_timeout,
+            max_connections,
+            codecs,
+        })
+    }
+}
+
+fn next_value(iter: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    iter.next().ok_or_else(|| format!("missing value for {flag}"))
+}
+
+fn parse_count(value: &str, flag: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid value for {flag}: {value}")),
+    }
+}
+
+fn parse_secs(value: &str, flag: &str) -> Result<Duration, String> {
+    match value.parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("invalid value for {flag}: {value}")),
+    }
+}
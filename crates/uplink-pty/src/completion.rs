@@ -0,0 +1,150 @@
+//! Shell completion for partial command lines
+//!
+//! Runs bash (with bash-completion when installed) in a short-lived helper
+//! process: no stdin, a minimal environment and a cap on returned candidates.
+//! The helper and everything it starts share a process group that is killed
+//! when the timeout expires, and run under CPU, memory and open-file limits.
+//! That bounds what a misbehaving completion function can cost; it is not
+//! isolation, and the helper can read and write whatever the user can.
+//!
+//! bash is used regardless of the user's shell. zsh's compsys only runs inside
+//! its line editor, which needs an interactive zsh on a terminal, so zsh users
+//! get bash's completions: commands that only ship zsh completions fall back
+//! to file names.
+//!
+//! bash-completion functions run programs from the folder being completed in
+//! (`git`, `make`, ...), which can execute code from the folder itself, so
+//! they are only loaded when the spawn hooks trust the cwd; otherwise the
+//! helper falls back to plain command and file name completion.
+
+use crate::protocol::ErrorCode;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+/// How long the helper may run before it's killed
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(2);
+/// Maximum number of candidates returned
+const MAX_CANDIDATES: usize = 500;
+/// CPU seconds each helper process may use
+const CPU_LIMIT_SECS: libc::rlim_t = 2;
+/// Address space each helper process may map
+const MEMORY_LIMIT_BYTES: libc::rlim_t = 512 * 1024 * 1024;
+/// Files each helper process may have open
+const OPEN_FILES_LIMIT: libc::rlim_t = 256;
+
+/// Drives bash's completion machinery for the line in $UPLINK_COMP_LINE
+const COMPLETE_SCRIPT: &str = r#"
+line="$UPLINK_COMP_LINE"
+if [[ -n "$UPLINK_COMP_FULL" && -r /usr/share/bash-completion/bash_completion ]]; then
+    . /usr/share/bash-completion/bash_completion
+fi
+read -ra COMP_WORDS <<< "$line"
+if [[ -z "$line" || "$line" == *" " ]]; then COMP_WORDS+=(""); fi
+COMP_CWORD=$(( ${#COMP_WORDS[@]} - 1 ))
+COMP_LINE="$line"
+COMP_POINT=${#line}
+cur="${COMP_WORDS[COMP_CWORD]}"
+prev=""
+(( COMP_CWORD > 0 )) && prev="${COMP_WORDS[COMP_CWORD-1]}"
+if (( COMP_CWORD == 0 )); then
+    compgen -c -- "$cur"
+    exit 0
+fi
+cmd="${COMP_WORDS[0]}"
+spec=$(complete -p "$cmd" 2>/dev/null)
+if [[ -z "$spec" ]] && declare -F _completion_loader >/dev/null; then
+    _completion_loader "$cmd" 2>/dev/null
+    spec=$(complete -p "$cmd" 2>/dev/null)
+fi
+func=""
+[[ $spec =~ -F\ ([^ ]+) ]] && func="${BASH_REMATCH[1]}"
+if [[ -n "$func" ]]; then
+    COMPREPLY=()
+    "$func" "$cmd" "$cur" "$prev" 2>/dev/null
+    printf '%s\n' "${COMPREPLY[@]}"
+else
+    compgen -f -- "$cur"
+fi
+"#;
+
+/// Return completion candidates for `line` as typed in `cwd`
+///
+/// `full` loads bash-completion; without it only commands and file names are offered
+pub async fn complete(line: &str, cwd: &str, full: bool) -> Result<Vec<String>, (ErrorCode, String)> {
+    let mut cmd = Command::new("bash");
+    cmd.arg("--noprofile")
+        .arg("--norc")
+        .arg("-c")
+        .arg(COMPLETE_SCRIPT)
+        .current_dir(cwd)
+        .env_clear()
+        .env("UPLINK_COMP_LINE", line)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .kill_on_drop(true);
+    // Only async-signal-safe calls between fork and exec
+    unsafe {
+        cmd.pre_exec(|| {
+            set_limit(libc::RLIMIT_CPU, CPU_LIMIT_SECS)?;
+            set_limit(libc::RLIMIT_AS, MEMORY_LIMIT_BYTES)?;
+            set_limit(libc::RLIMIT_NOFILE, OPEN_FILES_LIMIT)?;
+            set_limit(libc::RLIMIT_CORE, 0)
+        });
+    }
+    if full {
+        cmd.env("UPLINK_COMP_FULL", "1");
+    }
+    for var in ["PATH", "HOME", "USER", "LANG"] {
+        if let Ok(value) = std::env::var(var) {
+            cmd.env(var, value);
+        }
+    }
+
+    let mut child = cmd.spawn().map_err(|e| (ErrorCode::SpawnFailed, format!("failed to start completion helper: {}", e)))?;
+    let mut stdout = child.stdout.take().ok_or((ErrorCode::Io, "completion helper has no stdout".to_string()))?;
+    let run = async {
+        let mut out = Vec::new();
+        stdout.read_to_end(&mut out).await?;
+        child.wait().await?;
+        Ok::<_, std::io::Error>(out)
+    };
+    let out = match tokio::time::timeout(COMPLETION_TIMEOUT, run).await {
+        Ok(out) => out.map_err(|e| (ErrorCode::Io, format!("completion helper failed: {}", e)))?,
+        Err(_) => {
+            // bash hasn't been reaped, so its group id can't have been reused;
+            // this also gets whatever it left running in the background
+            if let Some(pgid) = child.id() {
+                unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+            }
+            return Err((ErrorCode::Timeout, "completion timed out".to_string()));
+        }
+    };
+
+    let mut candidates: Vec<String> = String::from_utf8_lossy(&out)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates.truncate(MAX_CANDIDATES);
+    Ok(candidates)
+}
+
+/// Lower both the soft and hard limit on `resource`, in the forked helper
+fn set_limit(resource: LimitResource, value: libc::rlim_t) -> std::io::Result<()> {
+    let limit = libc::rlimit { rlim_cur: value, rlim_max: value };
+    if unsafe { libc::setrlimit(resource, &limit) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type LimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type LimitResource = libc::c_int;
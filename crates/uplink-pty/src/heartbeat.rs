@@ -0,0 +1,103 @@
+//! Heartbeat: periodic pings and dead-client detection
+//!
+//! The server pings the client every `interval`. Once the client has shown it
+//! speaks the heartbeat protocol (by sending a Ping or Pong, or negotiating
+//! `CAP_HEARTBEAT`), a connection that stays silent for longer than `timeout`
+//! is considered dead and torn down. Its terminals are hung up, or with
+//! [`TimeoutAction::Detach`] left running with their output going only to the
+//! output log. Clients that never take part are pinged but never timed out.
+//!
+//! That is why heartbeats can be on by default with [`TimeoutAction::Kill`]:
+//! clients built before heartbeats existed never answer a ping and so are never
+//! timed out, and a client that does answer has promised to keep answering. A
+//! timed-out client is treated as if it had disconnected, which already hangs
+//! up its terminals.
+
+use crate::protocol::{Ping, MSG_PING};
+use crate::{send_msg, SocketWriter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// What happens to a timed-out client's terminals
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeoutAction {
+    /// Hang them up, as when the client disconnects
+    #[default]
+    Kill,
+    /// Keep them running until they exit or the server shuts down
+    Detach,
+}
+
+/// Heartbeat timing
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub on_timeout: TimeoutAction,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            on_timeout: TimeoutAction::default(),
+        }
+    }
+}
+
+/// Per-connection liveness state
+pub(crate) struct Liveness {
+    last_seen: Mutex<Instant>,
+    armed: AtomicBool,
+}
+
+impl Liveness {
+    pub fn new() -> Self {
+        Self {
+            last_seen: Mutex::new(Instant::now()),
+            armed: AtomicBool::new(false),
+        }
+    }
+
+    /// Record that a frame arrived from the client
+    pub fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    /// Record that the client takes part in the heartbeat protocol
+    pub fn arm(&self) {
+        self.armed.store(true, Ordering::Relaxed);
+    }
+
+    fn is_dead(&self, timeout: Duration) -> bool {
+        self.armed.load(Ordering::Relaxed) && self.last_seen.lock().unwrap().elapsed() > timeout
+    }
+}
+
+/// Ping the client until it stops answering or the socket fails
+/// Returns true if the client timed out; never completes when heartbeats are disabled
+pub(crate) async fn run(config: Option<HeartbeatConfig>, sock_write: SocketWriter, liveness: &Liveness) -> bool {
+    let Some(config) = config else {
+        return std::future::pending().await;
+    };
+
+    let mut ticker = tokio::time::interval(config.interval);
+    ticker.tick().await; // First tick fires immediately
+    let mut seq: u32 = 0;
+    loop {
+        ticker.tick().await;
+        if liveness.is_dead(config.timeout) {
+            warn!(timeout = ?config.timeout, "No heartbeat from client, dropping connection");
+            return true;
+        }
+        seq = seq.wrapping_add(1);
+        debug!(seq, "Sending ping");
+        if send_msg(&sock_write, MSG_PING, &Ping { seq }).await.is_err() {
+            warn!("Ping send failed, dropping connection");
+            return false;
+        }
+    }
+}
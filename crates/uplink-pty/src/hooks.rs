@@ -0,0 +1,161 @@
+//! Pre-spawn hooks for gating terminal creation
+//!
+//! Hooks run before a shell is spawned and can allow it, veto it with a reason,
+//! or ask the client to confirm (workspace-trust flows for untrusted folders)
+
+use std::path::{Path, PathBuf};
+
+/// The parts of a create request a hook can inspect
+#[derive(Debug, Clone, Copy)]
+pub struct SpawnContext<'a> {
+    pub shell: &'a str,
+    pub args: &'a [String],
+    pub cwd: &'a str,
+}
+
+/// Outcome of a pre-spawn hook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpawnDecision {
+    Allow,
+    /// Refuse to spawn, with a reason reported back to the client
+    Deny(String),
+    /// Ask the client to confirm before spawning, with a reason to display
+    Confirm(String),
+}
+
+/// A hook consulted before every terminal is spawned
+pub trait SpawnHook: Send + Sync {
+    fn check(&self, ctx: &SpawnContext<'_>) -> SpawnDecision;
+}
+
+/// Run all hooks and combine their decisions
+/// Any denial wins over confirmation; confirmation reasons are joined
+pub fn evaluate(hooks: &[std::sync::Arc<dyn SpawnHook>], ctx: &SpawnContext<'_>) -> SpawnDecision {
+    let mut confirm_reasons = Vec::new();
+    for hook in hooks {
+        match hook.check(ctx) {
+            SpawnDecision::Allow => {}
+            SpawnDecision::Deny(reason) => return SpawnDecision::Deny(reason),
+            SpawnDecision::Confirm(reason) => confirm_reasons.push(reason),
+        }
+    }
+    if confirm_reasons.is_empty() {
+        SpawnDecision::Allow
+    } else {
+        SpawnDecision::Confirm(confirm_reasons.join("; "))
+    }
+}
+
+/// What to do when a terminal is requested inside an untrusted folder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UntrustedAction {
+    #[default]
+    Deny,
+    Confirm,
+}
+
+/// Workspace-trust policy based on folder roots
+#[derive(Debug, Clone, Default)]
+pub struct TrustPolicy {
+    /// Terminals whose cwd is under one of these roots are gated
+    pub untrusted_roots: Vec<PathBuf>,
+    pub action: UntrustedAction,
+    /// Require client confirmation for every terminal, trusted or not
+    pub confirm_all: bool,
+}
+
+impl TrustPolicy {
+    fn is_untrusted(&self, cwd: &Path) -> bool {
+        // Resolve symlinks and `..` so a crafted cwd can't step around a root
+        let cwd = cwd.canonicalize().unwrap_or_else(|_| cwd.to_path_buf());
+        self.untrusted_roots.iter().any(|root| cwd.starts_with(root))
+    }
+}
+
+impl SpawnHook for TrustPolicy {
+    fn check(&self, ctx: &SpawnContext<'_>) -> SpawnDecision {
+        if self.is_untrusted(Path::new(ctx.cwd)) {
+            let reason = format!("folder {} is not trusted", ctx.cwd);
+            return match self.action {
+                UntrustedAction::Deny => SpawnDecision::Deny(reason),
+                UntrustedAction::Confirm => SpawnDecision::Confirm(reason),
+            };
+        }
+        if self.confirm_all {
+            return SpawnDecision::Confirm(format!("start {} in {}", ctx.shell, ctx.cwd));
+        }
+        SpawnDecision::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    struct Fixed(SpawnDecision);
+
+    impl SpawnHook for Fixed {
+        fn check(&self, _ctx: &SpawnContext<'_>) -> SpawnDecision {
+            self.0.clone()
+        }
+    }
+
+    fn ctx(cwd: &str) -> SpawnContext<'_> {
+        SpawnContext { shell: "/bin/sh", args: &[], cwd }
+    }
+
+    fn policy(root: &Path, action: UntrustedAction) -> TrustPolicy {
+        TrustPolicy { untrusted_roots: vec![root.canonicalize().unwrap()], action, confirm_all: false }
+    }
+
+    #[test]
+    fn no_hooks_allow() {
+        assert_eq!(evaluate(&[], &ctx("/")), SpawnDecision::Allow);
+    }
+
+    #[test]
+    fn deny_wins_over_confirm() {
+        let hooks: Vec<Arc<dyn SpawnHook>> = vec![
+            Arc::new(Fixed(SpawnDecision::Confirm("first".into()))),
+            Arc::new(Fixed(SpawnDecision::Deny("second".into()))),
+        ];
+        assert_eq!(evaluate(&hooks, &ctx("/")), SpawnDecision::Deny("second".into()));
+    }
+
+    #[test]
+    fn confirm_reasons_are_joined() {
+        let hooks: Vec<Arc<dyn SpawnHook>> = vec![
+            Arc::new(Fixed(SpawnDecision::Confirm("first".into()))),
+            Arc::new(Fixed(SpawnDecision::Allow)),
+            Arc::new(Fixed(SpawnDecision::Confirm("second".into()))),
+        ];
+        assert_eq!(evaluate(&hooks, &ctx("/")), SpawnDecision::Confirm("first; second".into()));
+    }
+
+    #[test]
+    fn trust_policy_gates_folders_under_untrusted_roots() {
+        let root = std::env::temp_dir().join(format!("uplink-hooks-{}", std::process::id()));
+        let inner = root.join("inner");
+        std::fs::create_dir_all(&inner).unwrap();
+
+        let deny = policy(&root, UntrustedAction::Deny);
+        assert!(matches!(deny.check(&ctx(inner.to_str().unwrap())), SpawnDecision::Deny(_)));
+        assert_eq!(deny.check(&ctx("/")), SpawnDecision::Allow);
+
+        let confirm = policy(&root, UntrustedAction::Confirm);
+        assert!(matches!(confirm.check(&ctx(root.to_str().unwrap())), SpawnDecision::Confirm(_)));
+
+        // `..` is resolved before comparing against the roots
+        let escaped = inner.join("..").join("inner");
+        assert!(matches!(deny.check(&ctx(escaped.to_str().unwrap())), SpawnDecision::Deny(_)));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn trust_policy_can_confirm_every_terminal() {
+        let policy = TrustPolicy { confirm_all: true, ..TrustPolicy::default() };
+        assert!(matches!(policy.check(&ctx("/")), SpawnDecision::Confirm(_)));
+    }
+}
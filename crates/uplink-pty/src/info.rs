@@ -0,0 +1,67 @@
+//! Process telemetry reported by MSG_SERVER_INFO, and connection ids for logs
+
+use crate::protocol::{BuildInfo, ServerInfoResponse};
+use crate::terminal;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// When the server started, set by the first [`mark_started`]
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+/// Clients being served by this process
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+/// Id for the next connection's tracing span
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Start the uptime clock if it isn't running yet
+pub(crate) fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+/// A span for a new connection, so its log lines can be told apart
+pub(crate) fn connection_span() -> tracing::Span {
+    tracing::info_span!("conn", id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Counts a client as connected for as long as it is held
+pub(crate) struct ConnectionGuard(());
+
+impl ConnectionGuard {
+    pub(crate) fn new() -> Self {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Clients being served right now
+pub(crate) fn connections() -> usize {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Describe this process as it is right now
+pub(crate) fn server_info(id: u32) -> ServerInfoResponse {
+    ServerInfoResponse {
+        id,
+        build: BuildInfo::new("uplink-pty", env!("CARGO_PKG_VERSION")),
+        git_hash: option_env!("UPLINK_GIT_HASH").map(str::to_string),
+        pid: std::process::id(),
+        uptime_ms: STARTED.elapsed().as_millis() as u64,
+        connections: connections() as u32,
+        terminals: terminal::live_count() as u32,
+        memory_rss: resident_memory(),
+    }
+}
+
+/// Resident set size from /proc/self/statm, in bytes
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (page_size > 0).then(|| pages * page_size as u64)
+}
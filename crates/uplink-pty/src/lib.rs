@@ -2,247 +2,1453 @@
 //!
 //! Provides multi-terminal support over a Unix socket using MessagePack protocol
 //! Wire format: [1 byte tag][4 byte length][MessagePack payload]
+//!
+//! Other programs can embed the service with [`PtyServer`], serving any
+//! `AsyncRead + AsyncWrite` stream, or drive a [`TerminalRegistry`] directly.
+
+pub mod cli;
+mod completion;
+pub mod heartbeat;
+pub mod hooks;
+mod info;
+pub mod listen;
+pub mod metrics;
+pub mod output_log;
+pub mod protocol;
+pub mod terminal;
+mod vsock;
+mod websocket;
 
-mod protocol;
-mod terminal;
+pub use listen::Endpoint;
+pub use terminal::{ExitStatus, Terminal, TerminalRegistry};
 
+use heartbeat::{HeartbeatConfig, Liveness, TimeoutAction};
+use listen::Connection;
+use hooks::{SpawnContext, SpawnDecision, SpawnHook};
+use output_log::OutputLogConfig;
 use protocol::*;
-use std::path::Path;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::{mpsc, Mutex};
-use tracing::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UnixStream;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use futures_util::StreamExt;
+use tokio_util::codec::{Decoder, FramedRead};
+use bytes::BytesMut;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicBool, Ordering};
+use uplink_proto::{
+    compress, FormatError, FrameCodec, FrameTooLarge, FrameWriter, PayloadCodec, SendError, DEFAULT_MAX_FRAME_LEN,
+};
 
-/// Start the PTY server, listening on the given Unix socket path
-pub async fn run(socket_path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let _ = std::fs::remove_file(socket_path);
-    let listener = UnixListener::bind(socket_path)?;
+/// How long a request may take when neither the config nor the request says otherwise
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Read-only requests a connection may have in flight at once
+const MAX_CONCURRENT_REQUESTS: usize = 32;
+/// Creates a connection may have waiting on the client's confirmation at once
+const MAX_PENDING_CONFIRMS: usize = 16;
+/// How long terminals get to exit after a shutdown hangup before they're killed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// How long to wait for killed terminals to be reaped
+const KILL_GRACE: Duration = Duration::from_secs(1);
 
-    // Print to stdout for Node.js startup detection, then log via tracing
-    println!("uplink-pty listening on {}", socket_path.display());
-    info!(path = %socket_path.display(), "uplink-pty listening");
+/// Environment variable the launcher passes the auth token in
+pub const AUTH_TOKEN_ENV: &str = "UPLINK_PTY_TOKEN";
+/// Environment variable naming the directory sidecars register their pid in
+pub const SIDECAR_DIR_ENV: &str = "UPLINK_SIDECAR_DIR";
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
+/// Writer task of a client connection, and the payload encoding it negotiated
+#[derive(Clone)]
+struct SocketWriter {
+    sock: FrameWriter,
+    format: Arc<std::sync::Mutex<WireFormat>>,
+    /// Terminal output goes out as flagged, possibly compressed, frames
+    compress: Arc<AtomicBool>,
+    /// Frames carry [`SERVICE_PTY`] after the tag; held while a frame is queued
+    multiplexed: Arc<std::sync::Mutex<bool>>,
+    /// Set on the writer of a request running in its own task: its answer is
+    /// only queued if the request is still in [`Running`], i.e. not cancelled
+    answering: Option<(u32, Running)>,
+}
+
+impl SocketWriter {
+    fn new(sock: Box<dyn AsyncWrite + Send + Unpin>, format: WireFormat) -> Self {
+        Self {
+            sock: FrameWriter::spawn(sock),
+            format: Arc::new(std::sync::Mutex::new(format)),
+            compress: Arc::new(AtomicBool::new(false)),
+            multiplexed: Arc::new(std::sync::Mutex::new(false)),
+            answering: None,
+        }
+    }
+
+    /// A writer for the task answering request `id`
+    fn answering(&self, id: Option<u32>, running: &Running) -> Self {
+        Self { answering: id.map(|id| (id, running.clone())), ..self.clone() }
+    }
+
+    fn compressing(&self) -> bool {
+        self.compress.load(Ordering::Relaxed)
+    }
+
+    fn set_compression(&self, on: bool) {
+        self.compress.store(on, Ordering::Relaxed);
+    }
+
+    /// Queue an encoded frame, adding the service id once multiplexing is on
+    /// `then_multiplex` turns it on for every frame after this one; the header
+    /// is picked and the frame queued under one lock, so nothing can slip
+    /// between the switch and the frame announcing it
+    ///
+    /// Returns `None` if the frame answers a cancelled request and was dropped
+    fn queue_frame(
+        &self,
+        tag: u8,
+        mut frame: Vec<u8>,
+        then_multiplex: bool,
+    ) -> Result<Option<impl Future<Output = Result<(), SendError>> + use<>>, SendError> {
+        // Held until the answer is queued, so a CANCEL either comes first or finds nothing to stop
+        let mut claim = self.answering.as_ref().map(|(id, running)| (*id, running.lock()));
+        if let Some((id, running)) = &mut claim
+            && running.remove(id).is_none()
+        {
+            debug!(id = *id, "Dropping the answer to a cancelled request");
+            return Ok(None);
+        }
+        let mut multiplexed = self.multiplexed.lock().unwrap_or_else(|e| e.into_inner());
+        if *multiplexed {
+            uplink_proto::add_service_id(&mut frame, SERVICE_PTY);
+        }
+        metrics::frame_sent(tag, frame.len());
+        let written = self.sock.queue_frame(frame)?;
+        *multiplexed |= then_multiplex;
+        Ok(Some(written))
+    }
+
+    fn format(&self) -> WireFormat {
+        *self.format.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_format(&self, format: WireFormat) {
+        *self.format.lock().unwrap_or_else(|e| e.into_inner()) = format;
+    }
+}
+/// Read half of a client connection
+type SocketReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Server configuration
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Hooks consulted before each terminal is spawned
+    pub spawn_hooks: Vec<Arc<dyn SpawnHook>>,
+    /// Ping clients and drop connections that stop answering
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// Tee terminal output to rotating log files
+    pub output_logs: Option<OutputLogConfig>,
+    /// Users allowed to connect over a Unix socket besides the server's own
+    pub allowed_uids: Vec<u32>,
+    /// Shared secret clients must present in an Auth request before anything else
+    pub auth_token: Option<String>,
+    /// Largest request payload accepted, in bytes; `DEFAULT_MAX_FRAME_LEN` if unset
+    pub max_frame_len: Option<usize>,
+    /// How long input and log requests may take; `DEFAULT_REQUEST_TIMEOUT` if unset
+    pub request_timeout: Option<Duration>,
+    /// Most clients served at once across all listeners; unlimited if unset
+    pub max_connections: Option<usize>,
+    /// Payload formats clients may switch to in Hello; MessagePack is always offered
+    pub codecs: Vec<WireFormat>,
+    /// Browser origins (`scheme://host[:port]`) allowed to use the WebSocket gateway
+    pub websocket_origins: Vec<String>,
+}
+
+/// Start the PTY server, listening on the given Unix socket endpoint
+pub async fn run(endpoint: impl Into<Endpoint>, config: Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    PtyServer::new(config).run(endpoint).await
+}
+
+/// An embeddable PTY server
+///
+/// ```no_run
+/// # async fn example(stream: tokio::net::TcpStream) {
+/// let server = uplink_pty::PtyServer::builder().build();
+/// server.serve_on(stream).await.unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct PtyServer {
+    config: Config,
+    /// Flipped to true by `shutdown`, shared by clones of the server
+    shutdown: Arc<watch::Sender<bool>>,
+    /// One permit per client connection the listeners may accept
+    connections: Arc<Semaphore>,
+}
+
+impl Default for PtyServer {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl PtyServer {
+    pub fn new(config: Config) -> Self {
+        info::mark_started();
+        let connections = Semaphore::new(config.max_connections.unwrap_or(Semaphore::MAX_PERMITS));
+        Self { config, shutdown: Arc::new(watch::channel(false).0), connections: Arc::new(connections) }
+    }
+
+    pub fn builder() -> PtyServerBuilder {
+        PtyServerBuilder::default()
+    }
+
+    /// Shut down gracefully: connected clients see their terminals hung up
+    /// (killed after a grace period) with lifecycle events, then `run` and
+    /// `serve_on` return
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Listen on `endpoint` and serve each client on its own task
+    /// Returns once shutdown is requested and every client has drained
+    pub async fn run(&self, endpoint: impl Into<Endpoint>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let endpoint = endpoint.into();
+        let listener = endpoint.bind()?;
+
+        // Print to stdout for Node.js startup detection, then log via tracing
+        println!("uplink-pty listening on {endpoint}");
+        info!(%endpoint, "uplink-pty listening");
+
+        let mut shutdown = self.shutdown.subscribe();
+        let mut clients = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(_) = clients.join_next() => continue,
+                _ = wait_shutdown(&mut shutdown) => {
+                    info!(clients = clients.len(), "Shutdown requested, no longer accepting clients");
+                    endpoint.cleanup();
+                    while clients.join_next().await.is_some() {}
+                    return Ok(());
+                }
+            };
+            let conn = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!(error = %e, "Accept error");
+                    continue;
+                }
+            };
+            if let Connection::Unix(stream) = &conn
+                && !self.peer_allowed(stream)
+            {
+                continue;
+            }
+            let Some(permit) = self.admit() else {
+                continue;
+            };
+            let server = self.clone();
+            clients.spawn(async move {
                 info!("Client connected");
-                if let Err(e) = handle_client(stream).await {
+                let served = match conn {
+                    Connection::Unix(stream) => server.serve(stream, true).await,
+                    Connection::Vsock(stream) => server.serve(stream, true).await,
+                };
+                if let Err(e) = served {
                     error!(error = %e, "Client error");
                 }
                 info!("Client disconnected");
+                drop(permit);
+            }.instrument(info::connection_span()));
+        }
+    }
+
+    /// Take a connection slot, or refuse the client if all are in use
+    pub(crate) fn admit(&self) -> Option<OwnedSemaphorePermit> {
+        match self.connections.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                warn!(max = ?self.config.max_connections, "Too many connections, rejecting client");
+                None
             }
+        }
+    }
+
+    /// Check a Unix socket peer's uid (SO_PEERCRED) against the allowed users
+    fn peer_allowed(&self, stream: &UnixStream) -> bool {
+        let uid = match stream.peer_cred() {
+            Ok(cred) => cred.uid(),
             Err(e) => {
-                error!(error = %e, "Accept error");
+                warn!(error = %e, "Rejecting client without peer credentials");
+                return false;
             }
+        };
+        let own_uid = unsafe { libc::geteuid() };
+        if uid == own_uid || self.config.allowed_uids.contains(&uid) {
+            return true;
         }
+        warn!(uid, "Rejecting client from disallowed user");
+        false
+    }
+
+    /// Serve the protocol over an already-connected stream until the client goes away
+    /// Terminals created on the stream are torn down when it ends
+    pub async fn serve_on<T>(&self, stream: T) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.serve(stream, true).instrument(info::connection_span()).await
+    }
+
+    /// [`serve_on`](Self::serve_on) inside the caller's connection span; `multiplex`
+    /// offers [`CAP_MULTIPLEX`] to clients on byte streams that can switch headers
+    pub(crate) async fn serve<T>(&self, stream: T, multiplex: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (sock_read, sock_write) = tokio::io::split(stream);
+        let shutdown = self.shutdown.subscribe();
+        handle_client(Box::new(sock_read), Box::new(sock_write), &self.config, shutdown, multiplex).await
+    }
+}
+
+/// Builder for [`PtyServer`]
+#[derive(Default)]
+pub struct PtyServerBuilder {
+    config: Config,
+}
+
+impl PtyServerBuilder {
+    /// Add a hook consulted before each terminal is spawned
+    pub fn spawn_hook(mut self, hook: impl SpawnHook + 'static) -> Self {
+        self.config.spawn_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Ping clients and drop connections that stop answering
+    pub fn heartbeat(mut self, heartbeat: HeartbeatConfig) -> Self {
+        self.config.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Keep a rotating on-disk log of each terminal's output
+    pub fn output_logs(mut self, output_logs: OutputLogConfig) -> Self {
+        self.config.output_logs = Some(output_logs);
+        self
+    }
+
+    /// Require clients to authenticate with `token` first
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.config.auth_token = Some(token.into());
+        self
+    }
+
+    /// Reject request frames with payloads over `max_frame_len` bytes
+    pub fn max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.config.max_frame_len = Some(max_frame_len);
+        self
+    }
+
+    /// Fail input and log requests that take longer than `timeout`
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Refuse clients beyond `max` concurrent connections
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    /// Let clients ask for `codec` payloads, e.g. JSON while debugging
+    pub fn codec(mut self, codec: WireFormat) -> Self {
+        self.config.codecs.push(codec);
+        self
+    }
+
+    /// Let pages from `origin` use the WebSocket gateway
+    pub fn websocket_origin(mut self, origin: impl Into<String>) -> Self {
+        self.config.websocket_origins.push(origin.into());
+        self
+    }
+
+    /// Let another user connect over the Unix socket
+    pub fn allow_uid(mut self, uid: u32) -> Self {
+        self.config.allowed_uids.push(uid);
+        self
+    }
+
+    pub fn build(self) -> PtyServer {
+        PtyServer::new(self.config)
     }
 }
 
 /// Handle a single client connection
 /// Spawns tasks for: PTY output forwarding, exit event forwarding, and request handling
-async fn handle_client(stream: UnixStream) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn handle_client(
+    sock_read: SocketReader,
+    sock_write: Box<dyn AsyncWrite + Send + Unpin>,
+    config: &Config,
+    mut shutdown: watch::Receiver<bool>,
+    multiplex: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     debug!("Setting up client handler");
-    let (sock_read, sock_write) = stream.into_split();
-    let sock_write = Arc::new(Mutex::new(sock_write));
-
-    let registry = Arc::new(Mutex::new(terminal::TerminalRegistry::new()));
+    let _connected = info::ConnectionGuard::new();
+    let sock_write = SocketWriter::new(sock_write, WireFormat::MessagePack);
 
-    // Channels for PTY events (output data and process exit)
-    let (output_tx, mut output_rx) = mpsc::channel::<(u32, Vec<u8>)>(64);
-    let (exit_tx, mut exit_rx) = mpsc::channel::<(u32, Option<i32>)>(16);
+    let mut registry = terminal::TerminalRegistry::new();
+    if let Some(output_logs) = &config.output_logs {
+        registry = registry.with_output_logs(output_logs.clone());
+    }
+    let registry = Arc::new(Mutex::new(registry));
 
-    // Forward PTY output to client as DataEvent messages
-    let sock_write_clone = sock_write.clone();
-    let output_task = tokio::spawn(async move {
-        debug!("Output task started");
-        while let Some((terminal_id, data)) = output_rx.recv().await {
-            debug!(terminal_id, bytes = data.len(), "Sending PTY output");
-            let event = DataEvent { terminal_id, data };
-            if send_msg(&sock_write_clone, MSG_DATA, &event).await.is_err() {
-                warn!("Output send failed, stopping output task");
-                break;
-            }
-        }
-        debug!("Output task ended");
-    });
+    // Channel for process exit events; output has a channel per terminal
+    let (exit_tx, mut exit_rx) = mpsc::channel::<(u32, terminal::ExitStatus)>(16);
 
-    // Forward PTY exit events to client as ExitEvent messages
+    // Forward PTY exit events to client as ExitEvent messages,
+    // relaunching terminals that asked for restart_on_exit
+    // During shutdown, exited terminals are dropped and `drained` fires once none are left
+    let drained = Arc::new(Notify::new());
     let sock_write_clone = sock_write.clone();
-    let exit_task = tokio::spawn(async move {
+    let registry_clone = registry.clone();
+    let drained_clone = drained.clone();
+    let restart_exit_tx = exit_tx.clone();
+    let mut exit_task = tokio::spawn(async move {
         debug!("Exit task started");
-        while let Some((terminal_id, code)) = exit_rx.recv().await {
-            info!(terminal_id, code = ?code, "Terminal exited");
-            let event = ExitEvent { terminal_id, code };
+        while let Some((terminal_id, status)) = exit_rx.recv().await {
+            info!(terminal_id, code = ?status.code, signal = ?status.signal, "Terminal exited");
+            let restarted = registry_clone
+                .lock()
+                .await
+                .restart(terminal_id, restart_exit_tx.clone());
+            match restarted {
+                Ok(Some(pid)) => {
+                    info!(terminal_id, pid, "Terminal restarted");
+                    let event = RestartedEvent { terminal_id, pid, code: status.code, signal: status.signal };
+                    let _ = send_msg(&sock_write_clone, MSG_RESTARTED, &event).await;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => error!(terminal_id, error = %e, "Failed to restart terminal"),
+            }
+            let event = ExitEvent { terminal_id, code: status.code, signal: status.signal };
             let _ = send_msg(&sock_write_clone, MSG_EXIT, &event).await;
+
+            let mut reg = registry_clone.lock().await;
+            if reg.is_draining() {
+                reg.remove(terminal_id);
+                if reg.terminals.is_empty() {
+                    drained_clone.notify_one();
+                }
+            }
         }
         debug!("Exit task ended");
-    });
+    }.in_current_span());
+
+    // Ping the client and watch for it going silent
+    let liveness = Liveness::new();
+    let heartbeat_task = heartbeat::run(config.heartbeat, sock_write.clone(), &liveness);
 
     // Handle incoming requests from client
-    let request_task = handle_requests(sock_read, sock_write.clone(), registry, output_tx, exit_tx);
+    let request_task = handle_requests(sock_read, sock_write.clone(), registry.clone(), exit_tx, config, &liveness, multiplex);
 
     // Run all tasks concurrently, exit when any completes
     debug!("Starting select on tasks");
-    tokio::select! {
-        _ = output_task => { debug!("Output task completed"); },
-        _ = exit_task => { debug!("Exit task completed"); },
+    let mut detach = false;
+    let result = tokio::select! {
+        _ = &mut exit_task => { debug!("Exit task completed"); Ok(()) },
+        timed_out = heartbeat_task => {
+            debug!("Heartbeat task completed");
+            detach = timed_out && config.heartbeat.is_some_and(|h| h.on_timeout == TimeoutAction::Detach);
+            Ok(())
+        },
+        _ = wait_shutdown(&mut shutdown) => {
+            drain_terminals(&registry, &drained, &sock_write).await;
+            Ok(())
+        },
         r = request_task => {
             debug!(result = ?r.is_ok(), "Request task completed");
-            r?;
+            r
         },
+    };
+
+    // The exit task holds the registry (and would restart terminals), so stop it
+    // and hang up whatever the client left running, unless it's being kept
+    exit_task.abort();
+    let terminals = std::mem::take(&mut registry.lock().await.terminals);
+    if detach && !terminals.is_empty() {
+        info!(terminals = terminals.len(), "Detaching terminals of timed-out client");
+        for (terminal_id, terminal) in terminals {
+            terminal.detach();
+            tokio::spawn(keep_detached(terminal_id, terminal, shutdown.clone()).in_current_span());
+        }
     }
+    result
+}
 
-    Ok(())
+/// Hold a detached terminal until its shell exits or the server shuts down
+async fn keep_detached(terminal_id: u32, terminal: terminal::Terminal, mut shutdown: watch::Receiver<bool>) {
+    let exited = terminal.wait_exited();
+    tokio::select! {
+        _ = exited => info!(terminal_id, "Detached terminal exited"),
+        _ = wait_shutdown(&mut shutdown) => debug!(terminal_id, "Hanging up detached terminal"),
+    }
+    drop(terminal);
+}
+
+/// Resolve once shutdown has been requested
+async fn wait_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|stop| *stop).await.is_err() {
+        // The server went away without asking for shutdown
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Hang up every terminal, give them SHUTDOWN_GRACE to exit, then kill the rest,
+/// reporting each stage to the client
+async fn drain_terminals(registry: &Arc<Mutex<terminal::TerminalRegistry>>, drained: &Notify, sock_write: &SocketWriter) {
+    let pending = {
+        let mut reg = registry.lock().await;
+        reg.start_draining();
+        reg.terminals.len()
+    };
+    info!(terminals = pending, "Shutting down, hanging up terminals");
+    send_lifecycle(sock_write, LifecycleStage::Draining, pending).await;
+    registry.lock().await.hang_up_all();
+
+    if pending > 0 && tokio::time::timeout(SHUTDOWN_GRACE, drained.notified()).await.is_err() {
+        let left = {
+            let reg = registry.lock().await;
+            reg.kill_all();
+            reg.terminals.len()
+        };
+        warn!(terminals = left, grace = ?SHUTDOWN_GRACE, "Terminals ignored hangup, killing");
+        send_lifecycle(sock_write, LifecycleStage::ForceKilled, left).await;
+        let _ = tokio::time::timeout(KILL_GRACE, drained.notified()).await;
+    }
+
+    let left = registry.lock().await.terminals.len();
+    info!(terminals = left, "Shutdown complete");
+    send_lifecycle(sock_write, LifecycleStage::Stopped, left).await;
+}
+
+async fn send_lifecycle(sock_write: &SocketWriter, stage: LifecycleStage, terminals: usize) {
+    let event = LifecycleEvent { stage, terminals: terminals as u32 };
+    if let Err(e) = send_msg(sock_write, MSG_LIFECYCLE, &event).await {
+        debug!(error = %e, "Failed to send lifecycle event");
+    }
+}
+
+/// Protocol state negotiated with a client
+struct Session {
+    version: u32,
+    capabilities: u64,
+    /// Client asked for minimal mode
+    minimal: bool,
+    /// Client presented the auth token, or none is required
+    authenticated: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        // Clients that never send Hello speak the original protocol
+        Self { version: 1, capabilities: 0, minimal: false, authenticated: false }
+    }
+}
+
+impl Session {
+    fn has(&self, capability: u64) -> bool {
+        self.capabilities & capability != 0
+    }
+}
+
+/// Requests answered from their own task, so CANCEL can stop them
+/// A request leaves the map either when its answer is queued or when it is
+/// cancelled, both under the map's lock, so only one of the two happens
+#[derive(Clone, Default)]
+struct Running(Arc<std::sync::Mutex<HashMap<u32, AbortHandle>>>);
+
+impl Running {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, AbortHandle>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `task`, which answers request `id`, remembering it for CANCEL
+    fn spawn(&self, id: Option<u32>, task: impl Future<Output = ()> + Send + 'static) {
+        let mut running = self.lock();
+        // Answered requests remove themselves; these were aborted or failed before answering
+        running.retain(|_, task| !task.is_finished());
+        // Spawned under the lock, so the task can't answer before it is tracked
+        let task = tokio::spawn(task);
+        if let Some(id) = id {
+            running.insert(id, task.abort_handle());
+        }
+    }
+
+    /// Stop request `id`; false if it isn't running or its answer is already queued
+    fn cancel(&self, id: u32) -> bool {
+        match self.lock().remove(&id) {
+            Some(task) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 /// Process incoming requests from the client
 /// Dispatches to appropriate handler based on message tag
 async fn handle_requests(
-    mut sock_read: tokio::net::unix::OwnedReadHalf,
-    sock_write: Arc<Mutex<tokio::net::unix::OwnedWriteHalf>>,
+    sock_read: SocketReader,
+    sock_write: SocketWriter,
     registry: Arc<Mutex<terminal::TerminalRegistry>>,
-    output_tx: mpsc::Sender<(u32, Vec<u8>)>,
-    exit_tx: mpsc::Sender<(u32, Option<i32>)>,
+    exit_tx: mpsc::Sender<(u32, terminal::ExitStatus)>,
+    config: &Config,
+    liveness: &Liveness,
+    multiplex: bool,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut session = Session { authenticated: config.auth_token.is_none(), ..Session::default() };
+    let max_frame_len = config.max_frame_len.unwrap_or(DEFAULT_MAX_FRAME_LEN);
+    let mut frames = FramedRead::new(sock_read, FrameCodec::with_max_frame_len(max_frame_len));
+
+    // Create requests waiting on a client confirmation, keyed by request id
+    let mut pending_confirms: HashMap<u32, CreateRequest> = HashMap::new();
+    let running = Running::default();
+    // Bounds the read-only requests running alongside the loop
+    let in_flight = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
+    let conn_span = tracing::Span::current();
+
     loop {
-        // Wire format: [1 byte tag][4 byte length BE][payload]
-        let mut tag = [0u8; 1];
-        if sock_read.read_exact(&mut tag).await.is_err() {
-            debug!("Client disconnected (read tag failed)");
-            break; // Client disconnected
+        let frame = match frames.next().await {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => {
+                // The payload can't be skipped without reading it, so the stream can't be resynced
+                if let Some(too_large) = FrameTooLarge::from_io(&e) {
+                    warn!(tag = too_large.tag, len = too_large.len, "Oversized frame, dropping client");
+                    let resp = ProtocolError::new(too_large.tag, None, too_large.to_string());
+                    let _ = send_msg(&sock_write, MSG_PROTOCOL_ERROR, &resp).await;
+                } else {
+                    error!(error = %e, "Failed to read message");
+                }
+                break;
+            }
+            None => {
+                debug!("Client disconnected");
+                break;
+            }
+        };
+        let header_len = frame.service.map_or(uplink_proto::FRAME_HEADER_LEN, |_| uplink_proto::MUX_FRAME_HEADER_LEN);
+        let (tag, msg_buf) = (frame.tag, frame.payload);
+        metrics::frame_received(header_len + msg_buf.len());
+        if let Some(service) = frame.service.filter(|&s| s != SERVICE_PTY) {
+            warn!(tag, service, "Frame for a service not served here");
+            let resp = ProtocolError::new(tag, peek_request_id(&msg_buf), format!("service {service} is not served on this socket"));
+            send_msg(&sock_write, MSG_PROTOCOL_ERROR, &resp).await?;
+            continue;
         }
+        let format = match payload_format(config, sock_write.format(), tag, &msg_buf) {
+            Ok(format) => format,
+            Err(message) => {
+                warn!(tag, %message, "Payload in a format this connection doesn't use");
+                let resp = ProtocolError::new(tag, None, message);
+                send_msg(&sock_write, MSG_PROTOCOL_ERROR, &resp).await?;
+                continue;
+            }
+        };
 
-        let mut len_buf = [0u8; 4];
-        if let Err(e) = sock_read.read_exact(&mut len_buf).await {
-            error!(error = %e, "Failed to read message length");
-            break;
-        }
-        let len = u32::from_be_bytes(len_buf) as usize;
+        // Everything logged while handling the frame, including from tasks it
+        // spawns, carries the request's tag and id
+        let span = info_span!("request", tag = tag_name(tag), id = peek_request_id(&msg_buf));
+        span.in_scope(|| debug!(len = msg_buf.len(), "Received message"));
+        liveness.touch();
 
-        let mut msg_buf = vec![0u8; len];
-        if let Err(e) = sock_read.read_exact(&mut msg_buf).await {
-            error!(error = %e, len, "Failed to read message body");
-            break;
+        if !session.authenticated {
+            let token = config.auth_token.as_deref().unwrap_or_default();
+            if !authenticate(tag, format, &msg_buf, token, &sock_write).instrument(span).await? {
+                break;
+            }
+            session.authenticated = true;
+            continue;
         }
 
-        debug!(tag = tag[0], len, "Received message");
-
-        match tag[0] {
-            MSG_CREATE => {
-                let req: CreateRequest = match rmp_serde::from_slice(&msg_buf) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!(error = %e, "Failed to decode CreateRequest");
-                        continue;
+        async {
+            let timer = metrics::RequestTimer::start(tag);
+            match tag {
+                MSG_AUTH => {
+                    // Already authenticated (or no token is configured)
+                    if let Ok(req) = format.decode::<AuthRequest>(&msg_buf) {
+                        send_msg(&sock_write, MSG_OK, &OkResponse { id: req.id }).await?;
                     }
-                };
-                info!(id = req.id, shell = %req.shell, cwd = %req.cwd, "Creating terminal");
-                let mut reg = registry.lock().await;
-                match reg.create(&req.shell, &req.args, &req.cwd, &req.env, req.cols, req.rows, output_tx.clone(), exit_tx.clone()) {
-                    Ok((terminal_id, pid)) => {
-                        info!(terminal_id, pid, "Terminal created");
-                        let resp = CreatedResponse { id: req.id, terminal_id, pid };
-                        send_msg(&sock_write, MSG_CREATED, &resp).await?;
+                }
+                MSG_HELLO => {
+                    let req: HelloRequest = match format.decode(&msg_buf) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "HelloRequest", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    let mut supported = SERVER_CAPABILITIES;
+                    if config.output_logs.is_none() {
+                        supported &= !CAP_OUTPUT_LOG;
                     }
-                    Err(e) => {
-                        error!(error = %e, "Failed to create terminal");
-                        let resp = ErrorResponse { id: req.id, message: e.to_string() };
-                        send_msg(&sock_write, MSG_ERROR, &resp).await?;
+                    if !multiplex {
+                        supported &= !CAP_MULTIPLEX;
+                    }
+                    if req.minimal {
+                        // Nothing that spawns helpers or holds output beyond a few chunks
+                        supported &= !(CAP_OUTPUT_LOG | CAP_COMPLETE);
+                    }
+                    let build = BuildInfo::new("uplink-pty", env!("CARGO_PKG_VERSION"));
+                    let ack = match req.hello.accept(build, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, supported) {
+                        Ok(ack) => ack,
+                        Err(resp) => {
+                            warn!(version = req.hello.version, "Client protocol version too old");
+                            send_msg(&sock_write, MSG_ERROR, &resp).await?;
+                            return Ok(());
+                        }
+                    };
+                    if req.minimal {
+                        registry.lock().await.disable_output_logs();
+                    }
+                    session.version = ack.version;
+                    session.capabilities = ack.capabilities;
+                    session.minimal = req.minimal;
+                    info!(
+                        version = session.version,
+                        capabilities = session.capabilities,
+                        minimal = session.minimal,
+                        "Negotiated protocol"
+                    );
+                    if session.has(CAP_HEARTBEAT) {
+                        liveness.arm();
+                    }
+                    let codec = match req.codec {
+                        Some(codec) if offers(config, codec) => codec,
+                        Some(codec) => {
+                            warn!(%codec, "Client asked for a codec this server doesn't offer");
+                            WireFormat::MessagePack
+                        }
+                        None => sock_write.format(),
+                    };
+                    sock_write.set_format(codec);
+                    sock_write.set_compression(session.has(CAP_COMPRESSION));
+                    let resp = HelloAck {
+                        ack,
+                        minimal: session.minimal,
+                        memory_budget: session
+                            .minimal
+                            .then_some((terminal::MINIMAL_OUTPUT_BUFFER * terminal::OUTPUT_CHUNK_SIZE) as u64),
+                        codec,
+                    };
+                    // Terminal output may already be flowing; everything queued after
+                    // the ack has to carry the service id
+                    let multiplexed = session.has(CAP_MULTIPLEX);
+                    let ack = uplink_proto::encode_frame_as(sock_write.format(), MSG_HELLO_ACK, &resp)?;
+                    if let Some(written) = sock_write.queue_frame(MSG_HELLO_ACK, ack, multiplexed)? {
+                        written.await?;
+                    }
+                    // The client holds everything else until it reads the ack
+                    if multiplexed {
+                        frames.decoder_mut().set_multiplexed(true);
                     }
                 }
-            }
-            MSG_INPUT => {
-                let req: InputRequest = match rmp_serde::from_slice(&msg_buf) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!(error = %e, "Failed to decode InputRequest");
-                        continue;
+                MSG_CREATE => {
+                    let mut req: CreateRequest = match format.decode(&msg_buf) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "CreateRequest", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    if session.minimal {
+                        let requested = req.output_buffer.unwrap_or(terminal::MINIMAL_OUTPUT_BUFFER);
+                        req.output_buffer = Some(requested.min(terminal::MINIMAL_OUTPUT_BUFFER));
                     }
-                };
-                debug!(terminal_id = req.terminal_id, bytes = req.data.len(), "Input");
-                let mut reg = registry.lock().await;
-                if let Some(term) = reg.get_mut(req.terminal_id) {
-                    if let Err(e) = term.write(&req.data) {
-                        warn!(error = %e, "Write to PTY failed");
+                    info!(id = req.id, shell = %req.shell, cwd = %req.cwd, "Creating terminal");
+                    let ctx = SpawnContext { shell: &req.shell, args: &req.args, cwd: &req.cwd };
+                    match hooks::evaluate(&config.spawn_hooks, &ctx) {
+                        SpawnDecision::Allow => {
+                            spawn_terminal(&req, &sock_write, &registry, &exit_tx, &conn_span).await?;
+                        }
+                        SpawnDecision::Deny(reason) => {
+                            warn!(id = req.id, reason = %reason, "Terminal creation denied");
+                            let resp = ErrorResponse::new(req.id, ErrorCode::Denied, format!("terminal creation denied: {}", reason));
+                            send_msg(&sock_write, MSG_ERROR, &resp).await?;
+                        }
+                        SpawnDecision::Confirm(reason) if !session.has(CAP_CONFIRM) => {
+                            // The client would never answer the prompt
+                            warn!(id = req.id, reason = %reason, "Terminal needs confirmation the client can't give");
+                            let resp = ErrorResponse::new(
+                                req.id,
+                                ErrorCode::Denied,
+                                format!("terminal creation needs confirmation, which this client does not support: {}", reason),
+                            );
+                            send_msg(&sock_write, MSG_ERROR, &resp).await?;
+                        }
+                        SpawnDecision::Confirm(reason) if pending_confirms.len() >= MAX_PENDING_CONFIRMS => {
+                            // Unanswered prompts would otherwise pile up without bound
+                            warn!(id = req.id, reason = %reason, "Too many terminals waiting for confirmation");
+                            let resp = ErrorResponse::new(
+                                req.id,
+                                ErrorCode::Denied,
+                                format!("too many terminal creations waiting for confirmation: {}", reason),
+                            );
+                            send_msg(&sock_write, MSG_ERROR, &resp).await?;
+                        }
+                        SpawnDecision::Confirm(reason) => {
+                            info!(id = req.id, reason = %reason, "Asking client to confirm terminal creation");
+                            let prompt = ConfirmPrompt { id: req.id, shell: req.shell.clone(), cwd: req.cwd.clone(), reason };
+                            send_msg(&sock_write, MSG_CONFIRM, &prompt).await?;
+                            pending_confirms.insert(req.id, req);
+                        }
                     }
-                } else {
-                    warn!(terminal_id = req.terminal_id, "Terminal not found for input");
                 }
-                let resp = OkResponse { id: req.id };
-                send_msg(&sock_write, MSG_OK, &resp).await?;
-            }
-            MSG_RESIZE => {
-                let req: ResizeRequest = match rmp_serde::from_slice(&msg_buf) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!(error = %e, "Failed to decode ResizeRequest");
-                        continue;
+                MSG_CONFIRM_REPLY => {
+                    let reply: ConfirmReply = match format.decode(&msg_buf) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "ConfirmReply", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    let Some(req) = pending_confirms.remove(&reply.id) else {
+                        warn!(id = reply.id, "Confirm reply for unknown request");
+                        return Ok(());
+                    };
+                    if reply.allow {
+                        info!(id = req.id, "Client confirmed terminal creation");
+                        spawn_terminal(&req, &sock_write, &registry, &exit_tx, &conn_span).await?;
+                    } else {
+                        let reason = reply.reason.unwrap_or_else(|| "rejected by client".into());
+                        info!(id = req.id, reason = %reason, "Client vetoed terminal creation");
+                        let resp = ErrorResponse::new(req.id, ErrorCode::Denied, format!("terminal creation denied: {}", reason));
+                        send_msg(&sock_write, MSG_ERROR, &resp).await?;
                     }
-                };
-                debug!(terminal_id = req.terminal_id, cols = req.cols, rows = req.rows, "Resize");
-                let reg = registry.lock().await;
-                if let Some(term) = reg.terminals.get(&req.terminal_id) {
-                    if let Err(e) = term.resize(req.cols, req.rows) {
-                        warn!(error = %e, "Resize failed");
+                }
+                MSG_GET_ENV | MSG_LIST_LOGS | MSG_READ_LOG => {
+                    // Read-only, so they can finish out of order without holding up input
+                    let permit = in_flight.clone().acquire_owned().await?;
+                    let id = peek_request_id(&msg_buf);
+                    let (registry, config, sock_write) = (registry.clone(), config.clone(), sock_write.answering(id, &running));
+                    running.spawn(id, async move {
+                        if let Err(e) = handle_simple_request(tag, &msg_buf, &registry, &config, &sock_write).await {
+                            warn!(tag, error = %e, "Failed to answer request");
+                        }
+                        drop((permit, timer));
+                    }.in_current_span());
+                }
+                tag if is_simple_request(tag) => {
+                    handle_simple_request(tag, &msg_buf, &registry, config, &sock_write).await?;
+                }
+                MSG_BATCH => {
+                    let req: BatchRequest = match format.decode(&msg_buf) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "BatchRequest", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    debug!(id = req.id, requests = req.requests.len(), parallel = req.parallel, "Batch");
+                    let resp = run_batch(req, &registry, config, sock_write.format()).await;
+                    send_msg(&sock_write, MSG_BATCH_RESULT, &resp).await?;
+                }
+                MSG_SERVER_INFO => {
+                    let req: ServerInfoRequest = match format.decode(&msg_buf) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "ServerInfoRequest", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    send_msg(&sock_write, MSG_SERVER_INFO_RESULT, &info::server_info(req.id)).await?;
+                }
+                MSG_COMPLETE => {
+                    let req: CompleteRequest = match format.decode(&msg_buf) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "CompleteRequest", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    debug!(id = req.id, line = %req.line, cwd = %req.cwd, "Completion");
+                    if session.minimal {
+                        let resp = ErrorResponse::new(req.id, ErrorCode::Disabled, "completion is disabled in minimal mode");
+                        send_msg(&sock_write, MSG_ERROR, &resp).await?;
+                        return Ok(());
                     }
+                    // Completion runs programs in the cwd, so it gets the same trust check as a shell would
+                    let ctx = SpawnContext { shell: "bash", args: &[], cwd: &req.cwd };
+                    let full = match hooks::evaluate(&config.spawn_hooks, &ctx) {
+                        SpawnDecision::Allow => true,
+                        SpawnDecision::Deny(reason) => {
+                            warn!(id = req.id, reason = %reason, "Completion denied by spawn hook");
+                            let resp = ErrorResponse::new(req.id, ErrorCode::Denied, format!("completion denied: {}", reason));
+                            send_msg(&sock_write, MSG_ERROR, &resp).await?;
+                            return Ok(());
+                        }
+                        SpawnDecision::Confirm(reason) => {
+                            debug!(id = req.id, reason = %reason, "Untrusted cwd, completing without bash-completion");
+                            false
+                        }
+                    };
+                    // The helper can take a while; answer from a task so input keeps flowing
+                    let id = req.id;
+                    let sock_write = sock_write.answering(Some(id), &running);
+                    running.spawn(Some(id), async move {
+                        let result = match completion::complete(&req.line, &req.cwd, full).await {
+                            Ok(candidates) => {
+                                let resp = CompletionsResponse { id: req.id, candidates };
+                                send_msg(&sock_write, MSG_COMPLETIONS, &resp).await
+                            }
+                            Err((code, message)) => {
+                                warn!(id = req.id, error = %message, "Completion failed");
+                                let resp = ErrorResponse::new(req.id, code, message);
+                                send_msg(&sock_write, MSG_ERROR, &resp).await
+                            }
+                        };
+                        if let Err(e) = result {
+                            warn!(error = %e, "Failed to send completion result");
+                        }
+                        drop(timer);
+                    }.in_current_span());
+                }
+                MSG_CANCEL => {
+                    let req: CancelRequest = match format.decode(&msg_buf) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "CancelRequest", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    // Requests handled inline have already been answered; only those
+                    // waiting on the client or running in their own task can be stopped.
+                    // A cancelled request gets no response.
+                    if pending_confirms.remove(&req.id).is_some() {
+                        info!(id = req.id, "Cancelled pending terminal creation");
+                    } else if running.cancel(req.id) {
+                        info!(id = req.id, "Cancelled request");
+                    } else {
+                        debug!(id = req.id, "Cancel for request that is not in flight");
+                    }
+                }
+                MSG_PING => {
+                    let ping: Ping = match format.decode(&msg_buf) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            report_decode_error(&sock_write, tag, &msg_buf, "Ping", &e).await?;
+                            return Ok(());
+                        }
+                    };
+                    liveness.arm();
+                    send_msg(&sock_write, MSG_PONG, &Pong { seq: ping.seq }).await?;
+                }
+                MSG_PONG => {
+                    liveness.arm();
+                }
+                _ => {
+                    warn!(tag, "Unknown message type");
+                    let resp = ProtocolError::new(tag, peek_request_id(&msg_buf), "unknown message type");
+                    send_msg(&sock_write, MSG_PROTOCOL_ERROR, &resp).await?;
                 }
-                let resp = OkResponse { id: req.id };
-                send_msg(&sock_write, MSG_OK, &resp).await?;
             }
-            MSG_KILL => {
-                let req: KillRequest = match rmp_serde::from_slice(&msg_buf) {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!(error = %e, "Failed to decode KillRequest");
-                        continue;
+            Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+        }
+        .instrument(span)
+        .await?;
+    }
+    Ok(())
+}
+
+fn is_simple_request(tag: u8) -> bool {
+    matches!(tag, MSG_INPUT | MSG_RESIZE | MSG_KILL | MSG_GET_ENV | MSG_LIST_LOGS | MSG_READ_LOG)
+}
+
+/// Run a batch's requests, collecting what each would have sent
+/// Answers are encoded in `format`, like everything else sent to the client
+async fn run_batch(
+    req: BatchRequest,
+    registry: &Mutex<terminal::TerminalRegistry>,
+    config: &Config,
+    format: WireFormat,
+) -> BatchResponse {
+    let run_one = |item: BatchItem| async move {
+        if !is_simple_request(item.tag) {
+            let resp = ProtocolError::new(item.tag, peek_request_id(&item.payload), "request cannot be batched");
+            let payload = format.encode(&resp).unwrap_or_default();
+            return vec![BatchItem { tag: MSG_PROTOCOL_ERROR, payload }];
+        }
+        if WireFormat::detect(&item.payload) != format {
+            let resp = ProtocolError::new(item.tag, None, format!("batched requests must be {format}, like the batch"));
+            let payload = format.encode(&resp).unwrap_or_default();
+            return vec![BatchItem { tag: MSG_PROTOCOL_ERROR, payload }];
+        }
+        let capture = Capture::default();
+        let writer = SocketWriter::new(Box::new(capture.clone()), format);
+        if let Err(e) = handle_simple_request(item.tag, &item.payload, registry, config, &writer).await {
+            warn!(tag = item.tag, error = %e, "Batched request failed");
+        }
+        capture.into_items()
+    };
+
+    let results = if req.parallel {
+        futures_util::future::join_all(req.requests.into_iter().map(run_one)).await
+    } else {
+        let mut results = Vec::with_capacity(req.requests.len());
+        for item in req.requests {
+            results.push(run_one(item).await);
+        }
+        results
+    };
+    BatchResponse { id: req.id, responses: results.into_iter().flatten().collect() }
+}
+
+/// In-memory stand-in for the socket, so batched requests reuse the normal handlers
+#[derive(Clone, Default)]
+struct Capture(Arc<std::sync::Mutex<BytesMut>>);
+
+impl Capture {
+    /// The frames written so far, as batch items
+    fn into_items(self) -> Vec<BatchItem> {
+        let mut buf = std::mem::take(&mut *self.0.lock().unwrap());
+        let mut codec = FrameCodec::new();
+        let mut items = Vec::new();
+        while let Ok(Some(frame)) = codec.decode(&mut buf) {
+            items.push(BatchItem { tag: frame.tag, payload: frame.payload });
+        }
+        items
+    }
+}
+
+impl AsyncWrite for Capture {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, data: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Requests that only need the registry and config, each answered on `sock_write`
+/// They can also run inside a batch, where `sock_write` collects the answers
+async fn handle_simple_request(
+    tag: u8,
+    msg_buf: &[u8],
+    registry: &Mutex<terminal::TerminalRegistry>,
+    config: &Config,
+    sock_write: &SocketWriter,
+) -> Result<(), SendError> {
+    match tag {
+        MSG_INPUT => {
+            let req: InputRequest = match sock_write.format().decode(msg_buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    report_decode_error(sock_write, tag, msg_buf, "InputRequest", &e).await?;
+                    return Ok(());
+                }
+            };
+            debug!(terminal_id = req.terminal_id, bytes = req.data.len(), "Input");
+            let limit = request_timeout(config, req.timeout_ms);
+            // Don't hold the registry while waiting on the PTY
+            let input = registry.lock().await.get_mut(req.terminal_id).map(|term| term.input());
+            if let Some(input) = input {
+                // A shell that stops reading fills the PTY; don't wedge the loop on it
+                match tokio::time::timeout(limit, input.write(&req.data)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!(error = %e, "Write to PTY failed"),
+                    Err(_) => {
+                        warn!(terminal_id = req.terminal_id, ?limit, "Write to PTY timed out");
+                        send_msg(sock_write, MSG_ERROR, &timeout_error(req.id, limit)).await?;
+                        return Ok(());
                     }
-                };
-                info!(terminal_id = req.terminal_id, "Killing terminal");
-                let mut reg = registry.lock().await;
-                reg.remove(req.terminal_id);
-                let resp = OkResponse { id: req.id };
-                send_msg(&sock_write, MSG_OK, &resp).await?;
+                }
+            } else {
+                warn!(terminal_id = req.terminal_id, "Terminal not found for input");
             }
-            _ => {
-                warn!(tag = tag[0], "Unknown message type");
-                let resp = ErrorResponse { id: 0, message: "unknown message type".into() };
-                send_msg(&sock_write, MSG_ERROR, &resp).await?;
+            let resp = OkResponse { id: req.id };
+            send_msg(sock_write, MSG_OK, &resp).await?;
+        }
+        MSG_RESIZE => {
+            let req: ResizeRequest = match sock_write.format().decode(msg_buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    report_decode_error(sock_write, tag, msg_buf, "ResizeRequest", &e).await?;
+                    return Ok(());
+                }
+            };
+            debug!(terminal_id = req.terminal_id, cols = req.cols, rows = req.rows, "Resize");
+            let mut reg = registry.lock().await;
+            if let Some(term) = reg.get_mut(req.terminal_id)
+                && let Err(e) = term.resize(req.cols, req.rows)
+            {
+                warn!(error = %e, "Resize failed");
             }
+            let resp = OkResponse { id: req.id };
+            send_msg(sock_write, MSG_OK, &resp).await?;
         }
+        MSG_KILL => {
+            let req: KillRequest = match sock_write.format().decode(msg_buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    report_decode_error(sock_write, tag, msg_buf, "KillRequest", &e).await?;
+                    return Ok(());
+                }
+            };
+            info!(terminal_id = req.terminal_id, "Killing terminal");
+            let mut reg = registry.lock().await;
+            reg.remove(req.terminal_id);
+            let resp = OkResponse { id: req.id };
+            send_msg(sock_write, MSG_OK, &resp).await?;
+        }
+        MSG_GET_ENV => {
+            let req: GetEnvRequest = match sock_write.format().decode(msg_buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    report_decode_error(sock_write, tag, msg_buf, "GetEnvRequest", &e).await?;
+                    return Ok(());
+                }
+            };
+            debug!(terminal_id = req.terminal_id, "Get environment");
+            let result = match registry.lock().await.terminals.get(&req.terminal_id) {
+                Some(term) => term.environment().map_err(|e| (ErrorCode::from(&e), e.to_string())),
+                None => Err((ErrorCode::NotFound, "terminal not found".to_string())),
+            };
+            match result {
+                Ok(env) => {
+                    let resp = EnvResponse { id: req.id, env };
+                    send_msg(sock_write, MSG_ENV, &resp).await?;
+                }
+                Err((code, message)) => {
+                    warn!(terminal_id = req.terminal_id, error = %message, "Get environment failed");
+                    let resp = ErrorResponse::new(req.id, code, message);
+                    send_msg(sock_write, MSG_ERROR, &resp).await?;
+                }
+            }
+        }
+        MSG_LIST_LOGS => {
+            let req: ListLogsRequest = match sock_write.format().decode(msg_buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    report_decode_error(sock_write, tag, msg_buf, "ListLogsRequest", &e).await?;
+                    return Ok(());
+                }
+            };
+            // Minimal-mode clients have logs turned off in their registry
+            let Some(output_logs) = registry.lock().await.output_logs().cloned() else {
+                let resp = ErrorResponse::new(req.id, ErrorCode::Disabled, "output logs are disabled");
+                send_msg(sock_write, MSG_ERROR, &resp).await?;
+                return Ok(());
+            };
+            let limit = request_timeout(config, req.timeout_ms);
+            let task = tokio::task::spawn_blocking(move || output_log::list(&output_logs));
+            let Ok(result) = tokio::time::timeout(limit, task).await else {
+                send_msg(sock_write, MSG_ERROR, &timeout_error(req.id, limit)).await?;
+                return Ok(());
+            };
+            match result.map_err(std::io::Error::other).and_then(|r| r) {
+                Ok(logs) => {
+                    send_msg(sock_write, MSG_LOGS, &LogsResponse { id: req.id, logs }).await?;
+                }
+                Err(e) => {
+                    let resp = ErrorResponse::new(req.id, ErrorCode::from(&e), e.to_string());
+                    send_msg(sock_write, MSG_ERROR, &resp).await?;
+                }
+            }
+        }
+        MSG_READ_LOG => {
+            let req: ReadLogRequest = match sock_write.format().decode(msg_buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    report_decode_error(sock_write, tag, msg_buf, "ReadLogRequest", &e).await?;
+                    return Ok(());
+                }
+            };
+            debug!(id = req.id, name = %req.name, tail = ?req.tail, "Read output log");
+            // Minimal-mode clients have logs turned off in their registry
+            let Some(output_logs) = registry.lock().await.output_logs().cloned() else {
+                let resp = ErrorResponse::new(req.id, ErrorCode::Disabled, "output logs are disabled");
+                send_msg(sock_write, MSG_ERROR, &resp).await?;
+                return Ok(());
+            };
+            let (name, tail) = (req.name.clone(), req.tail);
+            let limit = request_timeout(config, req.timeout_ms);
+            let task = tokio::task::spawn_blocking(move || output_log::read(&output_logs, &name, tail));
+            let Ok(result) = tokio::time::timeout(limit, task).await else {
+                send_msg(sock_write, MSG_ERROR, &timeout_error(req.id, limit)).await?;
+                return Ok(());
+            };
+            match result.map_err(std::io::Error::other).and_then(|r| r) {
+                Ok(data) => {
+                    let resp = LogDataResponse { id: req.id, name: req.name, data };
+                    send_msg(sock_write, MSG_LOG_DATA, &resp).await?;
+                }
+                Err(e) => {
+                    let resp = ErrorResponse::new(req.id, ErrorCode::from(&e), e.to_string());
+                    send_msg(sock_write, MSG_ERROR, &resp).await?;
+                }
+            }
+        }
+        _ => unreachable!("not a simple request: {}", tag),
     }
     Ok(())
 }
 
-/// Send a tagged MessagePack message to the client
-/// Returns a specific error type to allow callers to handle write failures appropriately
-async fn send_msg<T: serde::Serialize>(
-    sock: &Arc<Mutex<tokio::net::unix::OwnedWriteHalf>>,
+/// The timeout for a request: its own override, else the configured default
+fn request_timeout(config: &Config, timeout_ms: Option<u64>) -> Duration {
+    timeout_ms
+        .map(Duration::from_millis)
+        .or(config.request_timeout)
+        .unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+}
+
+fn timeout_error(id: u32, limit: Duration) -> ErrorResponse {
+    ErrorResponse::new(id, ErrorCode::Timeout, format!("request timed out after {}ms", limit.as_millis()))
+}
+
+/// Report a request that couldn't be decoded; the connection stays usable
+async fn report_decode_error(
+    sock_write: &SocketWriter,
     tag: u8,
-    msg: &T,
+    payload: &[u8],
+    what: &str,
+    err: &FormatError,
 ) -> Result<(), SendError> {
-    let data = rmp_serde::to_vec_named(msg).map_err(|e| SendError::Serialize(e.to_string()))?;
-    debug!(tag, len = data.len(), "Sending message");
-    let mut sock = sock.lock().await;
-    sock.write_all(&[tag]).await.map_err(|e| SendError::Write(e.to_string()))?;
-    sock.write_all(&(data.len() as u32).to_be_bytes()).await.map_err(|e| SendError::Write(e.to_string()))?;
-    sock.write_all(&data).await.map_err(|e| SendError::Write(e.to_string()))?;
-    Ok(())
+    error!(tag, error = %err, "Failed to decode {}", what);
+    let resp = ProtocolError::new(tag, peek_request_id(payload), format!("invalid {what}: {err}"));
+    send_msg(sock_write, MSG_PROTOCOL_ERROR, &resp).await
 }
 
-#[derive(Debug)]
-enum SendError {
-    Serialize(String),
-    Write(String),
+/// Check the first request on a connection that must authenticate
+/// Returns false if the client should be dropped
+async fn authenticate(tag: u8, format: WireFormat, msg_buf: &[u8], token: &str, sock_write: &SocketWriter) -> Result<bool, SendError> {
+    if tag != MSG_AUTH {
+        warn!(tag, "Request before authentication, dropping client");
+        return Ok(false);
+    }
+    let req: AuthRequest = match format.decode(msg_buf) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!(error = %e, "Failed to decode AuthRequest, dropping client");
+            return Ok(false);
+        }
+    };
+    if !tokens_match(req.token.as_bytes(), token.as_bytes()) {
+        warn!("Client failed authentication");
+        let resp = ErrorResponse::new(req.id, ErrorCode::AuthFailed, "authentication failed");
+        send_msg(sock_write, MSG_ERROR, &resp).await?;
+        return Ok(false);
+    }
+    info!("Client authenticated");
+    send_msg(sock_write, MSG_OK, &OkResponse { id: req.id }).await?;
+    Ok(true)
 }
 
-impl std::fmt::Display for SendError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SendError::Serialize(e) => write!(f, "serialization failed: {}", e),
-            SendError::Write(e) => write!(f, "socket write failed: {}", e),
+/// Compare tokens without exiting early on the first differing byte
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Spawn a terminal for an approved create request and report the result to the client
+async fn spawn_terminal(
+    req: &CreateRequest,
+    sock_write: &SocketWriter,
+    registry: &Arc<Mutex<terminal::TerminalRegistry>>,
+    exit_tx: &mpsc::Sender<(u32, terminal::ExitStatus)>,
+    conn_span: &tracing::Span,
+) -> Result<(), SendError> {
+    let mut reg = registry.lock().await;
+    match reg.create(req, exit_tx.clone()) {
+        Ok((terminal_id, pid, output_rx)) => {
+            info!(terminal_id, pid, "Terminal created");
+            // Output outlives the create request, so it logs under the connection
+            let span = info_span!(parent: conn_span, "terminal", id = terminal_id);
+            let forwarder = tokio::spawn(forward_output(terminal_id, output_rx, sock_write.clone()).instrument(span));
+            let log = reg.get_mut(terminal_id).and_then(|terminal| {
+                terminal.set_forwarder(forwarder.abort_handle());
+                terminal.log_name().map(str::to_string)
+            });
+            // A slow client must not keep other requests and the exit task off the registry
+            drop(reg);
+            let resp = CreatedResponse { id: req.id, terminal_id, pid, log };
+            send_msg(sock_write, MSG_CREATED, &resp).await
+        }
+        Err(e) => {
+            drop(reg);
+            error!(error = %e, "Failed to create terminal");
+            let resp = ErrorResponse::new(req.id, ErrorCode::SpawnFailed, e.to_string());
+            send_msg(sock_write, MSG_ERROR, &resp).await
+        }
+    }
+}
+
+/// Forward one terminal's output to the client as DataEvent messages
+/// Each terminal has its own task and channel, so a flood from one terminal
+/// only backs up that terminal
+async fn forward_output(terminal_id: u32, mut output_rx: mpsc::Receiver<Vec<u8>>, sock_write: SocketWriter) {
+    debug!(terminal_id, "Output task started");
+    while let Some(data) = output_rx.recv().await {
+        debug!(terminal_id, bytes = data.len(), "Sending PTY output");
+        let event = DataEvent { terminal_id, data };
+        if send_bulk(&sock_write, MSG_DATA, &event).await.is_err() {
+            warn!(terminal_id, "Output send failed, stopping output task");
+            break;
         }
     }
+    debug!(terminal_id, "Output task ended");
 }
 
-impl std::error::Error for SendError {}
+/// Send a tagged message to the client in its negotiated format
+async fn send_msg<T: serde::Serialize>(sock: &SocketWriter, tag: u8, msg: &T) -> Result<(), SendError> {
+    debug!(tag, "Sending message");
+    write_frame(sock, tag, uplink_proto::encode_frame_as(sock.format(), tag, msg)?).await
+}
+
+/// Send bulk data, as a flagged and possibly compressed frame if the client
+/// negotiated compression
+async fn send_bulk<T: serde::Serialize>(sock: &SocketWriter, tag: u8, msg: &T) -> Result<(), SendError> {
+    if !sock.compressing() {
+        return send_msg(sock, tag, msg).await;
+    }
+    debug!(tag, "Sending flagged message");
+    let frame = compress::encode_flagged_frame(sock.format(), tag, msg, compress::DEFAULT_COMPRESS_THRESHOLD)?;
+    write_frame(sock, tag, frame).await
+}
+
+/// Write an encoded frame, adding the service id once multiplexing is on
+async fn write_frame(sock: &SocketWriter, tag: u8, frame: Vec<u8>) -> Result<(), SendError> {
+    match sock.queue_frame(tag, frame, false)? {
+        Some(written) => written.await,
+        None => Ok(()),
+    }
+}
+
+/// The `id` field of a request payload in any format, for its tracing span and protocol errors
+fn peek_request_id(payload: &[u8]) -> Option<u32> {
+    #[derive(serde::Deserialize)]
+    struct RequestId {
+        id: Option<u32>,
+    }
+    WireFormat::detect(payload).decode::<RequestId>(payload).ok()?.id
+}
+
+/// Whether clients may ask for `format` payloads in Hello
+fn offers(config: &Config, format: WireFormat) -> bool {
+    format == WireFormat::MessagePack || config.codecs.contains(&format)
+}
+
+/// The format to decode a request payload in, or why it is refused
+/// Auth and Hello may come in any offered format, so a client can negotiate in
+/// the format it speaks; every other request must use the connection's format
+fn payload_format(config: &Config, connection: WireFormat, tag: u8, payload: &[u8]) -> Result<WireFormat, String> {
+    let format = WireFormat::detect(payload);
+    if format == connection || (matches!(tag, MSG_AUTH | MSG_HELLO) && offers(config, format)) {
+        Ok(format)
+    } else if offers(config, format) {
+        Err(format!("{format} payloads must be negotiated in Hello; this connection uses {connection}"))
+    } else {
+        Err(format!("{format} payloads are not enabled on this server (start it with --codec {format})"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uplink_proto::Hello;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn output_sent_during_hello_switches_to_multiplexed_frames_with_the_ack() {
+        let (client, server_stream) = tokio::io::duplex(64 * 1024);
+        let server = PtyServer::builder().build();
+        tokio::spawn(async move { server.serve_on(server_stream).await });
+        let (client_read, client_write) = tokio::io::split(client);
+        let mut frames = FramedRead::new(client_read, FrameCodec::new());
+        let writer = FrameWriter::spawn(client_write);
+
+        // Terminals flooding output before the client says Hello
+        let create = |id| CreateRequest {
+            id,
+            shell: "/bin/sh".into(),
+            args: vec!["-c".into(), "yes".into()],
+            cwd: "/".into(),
+            env: HashMap::new(),
+            cols: 80,
+            rows: 24,
+            restart_on_exit: false,
+            output_buffer: None,
+            overflow: Default::default(),
+        };
+        for id in 1..=4 {
+            uplink_proto::send_msg(&writer, MSG_CREATE, &create(id)).await.unwrap();
+            while frames.next().await.unwrap().unwrap().tag != MSG_CREATED {}
+        }
+        // Let output back up, so the ack is queued behind it and written late
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let hello = HelloRequest {
+            hello: Hello { id: 5, version: PROTOCOL_VERSION, capabilities: CAP_MULTIPLEX },
+            minimal: false,
+            codec: None,
+        };
+        uplink_proto::send_msg(&writer, MSG_HELLO, &hello).await.unwrap();
+        loop {
+            // Read slowly, so the ack waits for room while output is still being sent
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let frame = frames.next().await.unwrap().unwrap();
+            assert_eq!(frame.service, None);
+            if frame.tag == MSG_HELLO_ACK {
+                break;
+            }
+            assert_eq!(frame.tag, MSG_DATA);
+        }
+
+        frames.decoder_mut().set_multiplexed(true);
+        for _ in 0..200 {
+            let frame = frames.next().await.unwrap().unwrap();
+            assert_eq!((frame.tag, frame.service), (MSG_DATA, Some(SERVICE_PTY)));
+            WireFormat::MessagePack.decode::<DataEvent>(&frame.payload).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_must_use_the_format_negotiated_in_hello() {
+        let (client, server_stream) = tokio::io::duplex(64 * 1024);
+        let server = PtyServer::builder().codec(WireFormat::Cbor).build();
+        tokio::spawn(async move { server.serve_on(server_stream).await });
+        let (client_read, client_write) = tokio::io::split(client);
+        let mut frames = FramedRead::new(client_read, FrameCodec::new());
+        let writer = FrameWriter::spawn(client_write);
+
+        // CBOR is offered but not yet negotiated
+        uplink_proto::send_msg_as(&writer, WireFormat::Cbor, MSG_SERVER_INFO, &ServerInfoRequest { id: 1 }).await.unwrap();
+        assert_eq!(frames.next().await.unwrap().unwrap().tag, MSG_PROTOCOL_ERROR);
+
+        let hello = HelloRequest {
+            hello: Hello { id: 2, version: PROTOCOL_VERSION, capabilities: 0 },
+            minimal: false,
+            codec: Some(WireFormat::Cbor),
+        };
+        uplink_proto::send_msg_as(&writer, WireFormat::Cbor, MSG_HELLO, &hello).await.unwrap();
+        let ack = frames.next().await.unwrap().unwrap();
+        assert_eq!(ack.tag, MSG_HELLO_ACK);
+        assert_eq!(ack.decode_as::<HelloAck>(WireFormat::Cbor).unwrap().codec, WireFormat::Cbor);
+
+        // MessagePack is no longer accepted on this connection
+        uplink_proto::send_msg(&writer, MSG_SERVER_INFO, &ServerInfoRequest { id: 3 }).await.unwrap();
+        let frame = frames.next().await.unwrap().unwrap();
+        assert_eq!(frame.tag, MSG_PROTOCOL_ERROR);
+        frame.decode_as::<ProtocolError>(WireFormat::Cbor).unwrap();
+
+        uplink_proto::send_msg_as(&writer, WireFormat::Cbor, MSG_SERVER_INFO, &ServerInfoRequest { id: 4 }).await.unwrap();
+        assert_eq!(frames.next().await.unwrap().unwrap().tag, MSG_SERVER_INFO_RESULT);
+    }
+}
@@ -0,0 +1,169 @@
+//! Where the server listens
+//!
+//! A plain path binds a socket file. On Linux, `abstract:name` binds `name` in
+//! the abstract namespace instead: nothing is left behind in /tmp and there is
+//! no window between bind and chmod for another user to race.
+//!
+//! Under systemd socket activation the listening socket is inherited instead
+//! (`LISTEN_PID`/`LISTEN_FDS`), so the unit can start on first connection and
+//! restart without dropping the listener.
+//!
+//! Inside a VM guest, `vsock:port` (or `vsock:cid:port`) listens on AF_VSOCK
+//! so the host can connect without guest networking.
+
+use std::fmt;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use crate::vsock::{VsockListener, VsockStream, CID_ANY};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::warn;
+
+/// Prefix that selects the abstract namespace
+const ABSTRACT_PREFIX: &str = "abstract:";
+/// Permissions for socket files
+const SOCKET_MODE: u32 = 0o600;
+/// Prefix that selects AF_VSOCK
+const VSOCK_PREFIX: &str = "vsock:";
+/// First descriptor passed by socket activation (`SD_LISTEN_FDS_START`)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A Unix socket address the server can listen on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Socket file on disk, replaced if stale
+    Path(PathBuf),
+    /// Name in the Linux abstract socket namespace
+    Abstract(String),
+    /// Listening socket inherited from systemd socket activation
+    Systemd,
+    /// AF_VSOCK address; `cid` is usually [`CID_ANY`](crate::vsock::CID_ANY)
+    Vsock { cid: u32, port: u32 },
+}
+
+/// A bound listener for any [`Endpoint`]
+pub(crate) enum Listener {
+    Unix(UnixListener),
+    Vsock(VsockListener),
+}
+
+/// An accepted client connection
+pub(crate) enum Connection {
+    Unix(UnixStream),
+    Vsock(VsockStream),
+}
+
+impl Listener {
+    pub(crate) async fn accept(&self) -> std::io::Result<Connection> {
+        match self {
+            Listener::Unix(listener) => Ok(Connection::Unix(listener.accept().await?.0)),
+            Listener::Vsock(listener) => Ok(Connection::Vsock(listener.accept().await?.0)),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Parse `abstract:name`, `vsock:[cid:]port` or a filesystem path
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some(addr) = value.strip_prefix(VSOCK_PREFIX) {
+            return parse_vsock(addr).ok_or_else(|| format!("invalid vsock address: {value}"));
+        }
+        match value.strip_prefix(ABSTRACT_PREFIX) {
+            Some("") => Err("abstract socket name is empty".to_string()),
+            Some(name) => Ok(Endpoint::Abstract(name.to_string())),
+            None => Ok(Endpoint::Path(PathBuf::from(value))),
+        }
+    }
+
+    /// The socket passed by systemd, if this process was socket-activated
+    pub fn from_systemd() -> Option<Self> {
+        let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+        let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+        if pid != std::process::id() || fds == 0 {
+            return None;
+        }
+        if fds > 1 {
+            warn!(fds, "Socket activation passed several sockets, using the first");
+        }
+        Some(Endpoint::Systemd)
+    }
+
+    pub(crate) fn bind(&self) -> std::io::Result<Listener> {
+        let listener = match self {
+            Endpoint::Path(path) => {
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                // Only the owner may connect; peers are checked again on accept
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(SOCKET_MODE))?;
+                listener
+            }
+            Endpoint::Abstract(name) => bind_abstract(name)?,
+            Endpoint::Systemd => {
+                // Keep the listener out of spawned shells
+                if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(LISTEN_FDS_START) };
+                listener.local_addr()?; // Fails unless it really is a Unix socket
+                listener.set_nonblocking(true)?;
+                UnixListener::from_std(listener)?
+            }
+            Endpoint::Vsock { cid, port } => return Ok(Listener::Vsock(VsockListener::bind(*cid, *port)?)),
+        };
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Remove anything binding left behind; an inherited socket belongs to systemd
+    pub(crate) fn cleanup(&self) {
+        if let Endpoint::Path(path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// `port` or `cid:port`
+fn parse_vsock(addr: &str) -> Option<Endpoint> {
+    let (cid, port) = match addr.split_once(':') {
+        Some((cid, port)) => (cid.parse().ok()?, port.parse().ok()?),
+        None => (CID_ANY, addr.parse().ok()?),
+    };
+    Some(Endpoint::Vsock { cid, port })
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> std::io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    let listener = std::os::unix::net::UnixListener::bind_addr(&addr)?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_name: &str) -> std::io::Result<UnixListener> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "abstract sockets are Linux-only"))
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Path(path) => write!(f, "{}", path.display()),
+            Endpoint::Abstract(name) => write!(f, "{ABSTRACT_PREFIX}{name}"),
+            Endpoint::Systemd => write!(f, "systemd socket"),
+            Endpoint::Vsock { cid: CID_ANY, port } => write!(f, "{VSOCK_PREFIX}{port}"),
+            Endpoint::Vsock { cid, port } => write!(f, "{VSOCK_PREFIX}{cid}:{port}"),
+        }
+    }
+}
+
+impl From<&Path> for Endpoint {
+    fn from(path: &Path) -> Self {
+        Endpoint::Path(path.to_path_buf())
+    }
+}
+
+impl From<PathBuf> for Endpoint {
+    fn from(path: PathBuf) -> Self {
+        Endpoint::Path(path)
+    }
+}
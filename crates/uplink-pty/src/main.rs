@@ -1,29 +1,20 @@
-use std::path::PathBuf;
 use tracing::{error, info};
-use tracing_appender::rolling;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use uplink_pty::cli::{self, Args};
 
 #[tokio::main]
 async fn main() {
-    // Log to /tmp/uplink-pty.log
-    let log_dir = PathBuf::from("/tmp");
-    let file_appender = rolling::never(&log_dir, "uplink-pty.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-
-    tracing_subscriber::registry()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug")))
-        .with(fmt::layer().with_writer(non_blocking).with_ansi(false))
-        .with(fmt::layer().with_writer(std::io::stderr))
-        .init();
-
+    let _guard = cli::init_logging("uplink-pty.log");
     info!("uplink-pty starting");
 
-    let socket_path = std::env::args()
-        .nth(1)
-        .map(PathBuf::from)
-        .unwrap_or_else(|| PathBuf::from("/tmp/uplink-pty.sock"));
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            error!(error = %e, "Invalid arguments");
+            std::process::exit(2);
+        }
+    };
 
-    if let Err(e) = uplink_pty::run(&socket_path).await {
+    if let Err(e) = cli::run(args).await {
         error!(error = %e, "Fatal error");
         std::process::exit(1);
     }
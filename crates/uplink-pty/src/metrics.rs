@@ -0,0 +1,191 @@
+//! Prometheus metrics
+//!
+//! Counters are process-wide and always collected; `--metrics <addr>` serves
+//! them as Prometheus text at `GET /metrics` so the sidecar can be scraped like
+//! any other daemon. The endpoint is unauthenticated and read-only; bind to
+//! loopback.
+
+use crate::protocol::{tag_name, MSG_ERROR, MSG_PROTOCOL_ERROR};
+use crate::{terminal, wait_shutdown, PtyServer};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0, 5.0];
+/// Largest request head a scraper may send
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// How long a scraper gets to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+static PROTOCOL_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Request count and latency per message type
+static REQUESTS: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bucket, &bound) in self.buckets.iter_mut().zip(&LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Times a request from when its frame arrives until it is dropped
+pub(crate) struct RequestTimer {
+    tag: u8,
+    started: Instant,
+}
+
+impl RequestTimer {
+    pub(crate) fn start(tag: u8) -> Self {
+        Self { tag, started: Instant::now() }
+    }
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        let secs = self.started.elapsed().as_secs_f64();
+        let mut requests = REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+        requests.entry(tag_name(self.tag)).or_default().observe(secs);
+    }
+}
+
+/// Count a frame read from a client
+pub(crate) fn frame_received(len: usize) {
+    BYTES_RECEIVED.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+/// Count a frame written to a client
+pub(crate) fn frame_sent(tag: u8, len: usize) {
+    BYTES_SENT.fetch_add(len as u64, Ordering::Relaxed);
+    match tag {
+        MSG_ERROR => ERRORS.fetch_add(1, Ordering::Relaxed),
+        MSG_PROTOCOL_ERROR => PROTOCOL_ERRORS.fetch_add(1, Ordering::Relaxed),
+        _ => 0,
+    };
+}
+
+/// Everything collected so far, in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    let requests = REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let _ = writeln!(out, "# HELP uplink_pty_requests_total Requests handled, by message type.");
+    let _ = writeln!(out, "# TYPE uplink_pty_requests_total counter");
+    for (tag, hist) in requests.iter() {
+        let _ = writeln!(out, "uplink_pty_requests_total{{tag=\"{tag}\"}} {}", hist.count);
+    }
+
+    let _ = writeln!(out, "# HELP uplink_pty_request_duration_seconds Time from receiving a request to finishing it.");
+    let _ = writeln!(out, "# TYPE uplink_pty_request_duration_seconds histogram");
+    for (tag, hist) in requests.iter() {
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&hist.buckets) {
+            let _ = writeln!(out, "uplink_pty_request_duration_seconds_bucket{{tag=\"{tag}\",le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "uplink_pty_request_duration_seconds_bucket{{tag=\"{tag}\",le=\"+Inf\"}} {}", hist.count);
+        let _ = writeln!(out, "uplink_pty_request_duration_seconds_sum{{tag=\"{tag}\"}} {}", hist.sum);
+        let _ = writeln!(out, "uplink_pty_request_duration_seconds_count{{tag=\"{tag}\"}} {}", hist.count);
+    }
+    drop(requests);
+
+    let _ = writeln!(out, "# HELP uplink_pty_errors_total Error responses sent, by kind.");
+    let _ = writeln!(out, "# TYPE uplink_pty_errors_total counter");
+    let _ = writeln!(out, "uplink_pty_errors_total{{kind=\"error\"}} {}", ERRORS.load(Ordering::Relaxed));
+    let _ = writeln!(out, "uplink_pty_errors_total{{kind=\"protocol_error\"}} {}", PROTOCOL_ERRORS.load(Ordering::Relaxed));
+
+    let counters = [
+        ("uplink_pty_received_bytes_total", "Bytes of frames read from clients.", BYTES_RECEIVED.load(Ordering::Relaxed)),
+        ("uplink_pty_sent_bytes_total", "Bytes of frames written to clients.", BYTES_SENT.load(Ordering::Relaxed)),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+    }
+
+    let gauges = [
+        ("uplink_pty_connections", "Clients currently connected.", crate::info::connections() as u64),
+        ("uplink_pty_terminals", "Terminals currently running.", terminal::live_count() as u64),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}");
+    }
+    out
+}
+
+impl PtyServer {
+    /// Serve `GET /metrics` on `addr` until shutdown
+    pub async fn run_metrics(&self, addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(addr = %listener.local_addr()?, "Metrics listening");
+
+        let mut shutdown = self.shutdown.subscribe();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = wait_shutdown(&mut shutdown) => return Ok(()),
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = answer_scrape(stream).await {
+                            debug!(error = %e, "Metrics request failed");
+                        }
+                    });
+                }
+                Err(e) => debug!(error = %e, "Failed to accept metrics connection"),
+            }
+        }
+    }
+}
+
+/// Read one HTTP request and answer it, then close the connection
+async fn answer_scrape(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    tokio::time::timeout(READ_TIMEOUT, async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "scraper sent no request"))??;
+
+    let request_line = head.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let response = match (method, path) {
+        (b"GET", b"/metrics") => {
+            let body = render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
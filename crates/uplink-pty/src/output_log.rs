@@ -0,0 +1,352 @@
+//! Terminal output logs: a copy of each terminal's output on disk
+//!
+//! Every terminal gets its own log, named when it's created and kept across
+//! restarts. A log is a current file `<name>.log` plus rotated segments
+//! `<name>.log.1` (newest) to `<name>.log.<max_files - 1>` (oldest). Logs
+//! untouched for longer than the retention period are deleted, so output
+//! from a terminal closed hours ago can still be fetched. Logs still open in
+//! this process are never deleted, however long their terminal has been idle.
+//!
+//! Logs hold everything the shell printed, so the directory is private to the
+//! server's user (0700, and it must be owned by that user) and files are
+//! created 0600 without following symlinks.
+
+use crate::protocol::LogInfo;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, warn, Instrument};
+
+/// Most bytes returned by a single read, so the reply fits comfortably in a frame
+pub const MAX_READ_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Chunks queued for the background writer before the terminal's reader waits
+const WRITE_QUEUE: usize = 64;
+/// Most queued output written in one go
+const WRITE_BATCH_BYTES: usize = 256 * 1024;
+
+/// Distinguishes logs created within the same millisecond
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+/// Names of the logs open in this process, once per open [`OutputLog`]
+static OPEN_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Where output logs live and how much of them is kept
+#[derive(Debug, Clone)]
+pub struct OutputLogConfig {
+    pub dir: PathBuf,
+    /// Size at which the current file is rotated
+    pub max_file_bytes: u64,
+    /// Files kept per terminal, including the current one
+    pub max_files: usize,
+    /// Logs not written to for this long are deleted
+    pub retention: Duration,
+}
+
+impl Default for OutputLogConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_dir(),
+            max_file_bytes: 1024 * 1024,
+            max_files: 4,
+            retention: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Writer for one terminal's log
+pub struct OutputLog {
+    config: OutputLogConfig,
+    name: String,
+    file: File,
+    written: u64,
+}
+
+impl OutputLog {
+    /// Start a new log, pruning expired ones first
+    pub fn create(config: &OutputLogConfig) -> io::Result<Self> {
+        ensure_private_dir(&config.dir)?;
+        prune(config);
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{}-{}-{}", millis, std::process::id(), seq);
+        Self::open(config, name)
+    }
+
+    /// Continue an existing log, e.g. when a terminal restarts
+    pub fn open(config: &OutputLogConfig, name: String) -> io::Result<Self> {
+        let file = open_append(&segment(config, &name, 0))?;
+        let written = file.metadata()?.len();
+        OPEN_LOGS.lock().unwrap_or_else(|e| e.into_inner()).push(name.clone());
+        Ok(Self { config: config.clone(), name, file, written })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Append output, rotating first if it would overflow the current file
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.written > 0 && self.written + data.len() as u64 > self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.config.max_files.max(1);
+        let _ = fs::remove_file(segment(&self.config, &self.name, keep - 1));
+        for i in (0..keep - 1).rev() {
+            let from = segment(&self.config, &self.name, i);
+            if from.exists() {
+                fs::rename(&from, segment(&self.config, &self.name, i + 1))?;
+            }
+        }
+        self.file = open_append(&segment(&self.config, &self.name, 0))?;
+        self.written = 0;
+        debug!(log = %self.name, "Rotated output log");
+        Ok(())
+    }
+
+    /// Hand the log to a background task that writes whatever is queued
+    ///
+    /// Writes and rotation run on the blocking pool, so a slow disk holds up
+    /// the terminal's output only once the queue is full.
+    pub fn spawn_writer(self) -> LogWriter {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(WRITE_QUEUE);
+        let task = tokio::spawn(async move {
+            let mut log = self;
+            while let Some(mut data) = rx.recv().await {
+                while data.len() < WRITE_BATCH_BYTES
+                    && let Ok(more) = rx.try_recv()
+                {
+                    data.extend_from_slice(&more);
+                }
+                let result;
+                (log, result) = match tokio::task::spawn_blocking(move || {
+                    let result = log.write(&data);
+                    (log, result)
+                })
+                .await
+                {
+                    Ok(done) => done,
+                    Err(e) => {
+                        warn!(error = %e, "Output log writer panicked, disabling log");
+                        return;
+                    }
+                };
+                if let Err(e) = result {
+                    warn!(log = %log.name, error = %e, "Output log write failed, disabling log");
+                    return;
+                }
+            }
+        }.in_current_span());
+        LogWriter { tx, task }
+    }
+}
+
+impl Drop for OutputLog {
+    fn drop(&mut self) {
+        let mut open = OPEN_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = open.iter().position(|name| *name == self.name) {
+            open.swap_remove(i);
+        }
+    }
+}
+
+/// Queue feeding an [`OutputLog`] written in the background
+pub struct LogWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl LogWriter {
+    /// Queue output; false once the log has failed and been disabled
+    pub async fn write(&self, data: &[u8]) -> bool {
+        self.tx.send(data.to_vec()).await.is_ok()
+    }
+
+    /// Wait until everything queued is on disk
+    pub async fn finish(self) {
+        drop(self.tx);
+        let _ = self.task.await;
+    }
+}
+
+/// List stored logs, most recently written first
+pub fn list(config: &OutputLogConfig) -> io::Result<Vec<LogInfo>> {
+    prune(config);
+    let mut logs = Vec::new();
+    for entry in read_dir_or_empty(&config.dir)? {
+        let file_name = entry?.file_name();
+        let Some(name) = file_name.to_str().and_then(|n| n.strip_suffix(".log")) else {
+            continue;
+        };
+        let mut size = 0;
+        let mut modified = 0;
+        for path in segments(config, name) {
+            if let Ok(meta) = fs::metadata(&path) {
+                size += meta.len();
+                modified = modified.max(unix_secs(meta.modified().ok()));
+            }
+        }
+        logs.push(LogInfo { name: name.to_string(), size, modified });
+    }
+    logs.sort_by_key(|log| std::cmp::Reverse(log.modified));
+    Ok(logs)
+}
+
+/// Read a log oldest-first, or only its last `tail` bytes
+/// At most MAX_READ_BYTES are returned, taken from the end
+pub fn read(config: &OutputLogConfig, name: &str, tail: Option<u64>) -> io::Result<Vec<u8>> {
+    if !valid_name(name) || !segment(config, name, 0).exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no output log named {name}")));
+    }
+    let mut remaining = tail.unwrap_or(MAX_READ_BYTES).min(MAX_READ_BYTES);
+
+    // Walk newest to oldest taking what's still wanted, then put it back in order
+    let mut parts = Vec::new();
+    for path in segments(config, name).into_iter().rev() {
+        if remaining == 0 {
+            break;
+        }
+        let Ok(mut file) = OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(&path) else {
+            continue;
+        };
+        let len = file.metadata()?.len();
+        let take = len.min(remaining);
+        file.seek(SeekFrom::Start(len - take))?;
+        let mut buf = Vec::with_capacity(take as usize);
+        file.take(take).read_to_end(&mut buf)?;
+        remaining -= buf.len() as u64;
+        parts.push(buf);
+    }
+    parts.reverse();
+    Ok(parts.concat())
+}
+
+/// Delete logs whose newest file is older than the retention period
+/// Logs of terminals still running here are kept, however idle
+fn prune(config: &OutputLogConfig) {
+    let Ok(entries) = read_dir_or_empty(&config.dir) else {
+        return;
+    };
+    let now = SystemTime::now();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(name) = file_name.to_str().and_then(|n| n.strip_suffix(".log")) else {
+            continue;
+        };
+        if OPEN_LOGS.lock().unwrap_or_else(|e| e.into_inner()).iter().any(|open| open == name) {
+            continue;
+        }
+        let newest = segments(config, name)
+            .iter()
+            .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+            .max();
+        let expired = newest
+            .and_then(|t| now.duration_since(t).ok())
+            .is_some_and(|age| age > config.retention);
+        if expired {
+            debug!(log = name, "Removing expired output log");
+            for path in segments(config, name) {
+                if let Err(e) = fs::remove_file(&path)
+                    && e.kind() != io::ErrorKind::NotFound
+                {
+                    warn!(path = %path.display(), error = %e, "Failed to remove output log");
+                }
+            }
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/uplink-pty-logs`, or a per-user directory under the temp dir
+fn default_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => PathBuf::from(runtime).join("uplink-pty-logs"),
+        _ => std::env::temp_dir().join(format!("uplink-pty-logs-{}", unsafe { libc::geteuid() })),
+    }
+}
+
+/// Create the log directory 0700, or check an existing one isn't a symlink
+/// or somebody else's
+fn ensure_private_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() {
+        return Err(io::Error::other(format!("{} is not a directory", dir.display())));
+    }
+    let uid = unsafe { libc::geteuid() };
+    if meta.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is owned by uid {}, not {}", dir.display(), meta.uid(), uid),
+        ));
+    }
+    Ok(())
+}
+
+/// Open a log file for appending, private to this user and never through a symlink
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// Every file a log may have, oldest first
+fn segments(config: &OutputLogConfig, name: &str) -> Vec<PathBuf> {
+    (0..config.max_files.max(1)).rev().map(|i| segment(config, name, i)).collect()
+}
+
+fn segment(config: &OutputLogConfig, name: &str, index: usize) -> PathBuf {
+    if index == 0 {
+        config.dir.join(format!("{name}.log"))
+    } else {
+        config.dir.join(format!("{name}.log.{index}"))
+    }
+}
+
+/// Log names are generated by the server; reject anything that could escape the directory
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+fn read_dir_or_empty(dir: &Path) -> io::Result<Box<dyn Iterator<Item = io::Result<fs::DirEntry>>>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(Box::new(entries)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Box::new(std::iter::empty())),
+        Err(e) => Err(e),
+    }
+}
+
+fn unix_secs(time: Option<SystemTime>) -> u64 {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_keeps_logs_that_are_still_open() {
+        let dir = std::env::temp_dir().join(format!("uplink-pty-prune-test-{}", std::process::id()));
+        let config = OutputLogConfig { dir: dir.clone(), retention: Duration::ZERO, ..OutputLogConfig::default() };
+        let mut log = OutputLog::create(&config).unwrap();
+        log.write(b"idle since").unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(list(&config).unwrap().len(), 1);
+        drop(log);
+        assert!(list(&config).unwrap().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
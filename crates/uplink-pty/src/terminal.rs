@@ -1,31 +1,379 @@
 //! Terminal management using portable-pty
 
+use crate::output_log::{OutputLog, OutputLogConfig};
+use crate::protocol::{CreateRequest, OverflowPolicy};
 use portable_pty::{native_pty_system, Child, CommandBuilder, MasterPty, PtySize};
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use tokio::sync::mpsc;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::{mpsc, watch};
+use tokio::task::AbortHandle;
+use tracing::{warn, Instrument};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Output chunks buffered per terminal when the request doesn't say
+const DEFAULT_OUTPUT_BUFFER: usize = 64;
+/// Upper bound on a requested output buffer
+const MAX_OUTPUT_BUFFER: usize = 4096;
+/// Output buffer for every terminal of a minimal-mode client
+pub const MINIMAL_OUTPUT_BUFFER: usize = 4;
+/// Largest chunk of output read from the PTY at once
+pub const OUTPUT_CHUNK_SIZE: usize = 4096;
+
+/// First wait between polls for an exited terminal's child
+const REAP_POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Longest wait between polls; a child that outlives its PTY is checked this often
+const MAX_REAP_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How a terminal's child process ended
+/// Exactly one of `code` and `signal` is set once the child has been reaped
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExitStatus {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+impl ExitStatus {
+    /// Wait for the child to exit without tying up a thread and decode its wait status
+    async fn wait(pid: u32) -> Self {
+        if pid == 0 {
+            return Self::default();
+        }
+        // Where pidfds exist the kernel says when the child exits; polling is the fallback
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = pidfd_open(pid) {
+            let _ = pidfd.readable().await;
+        }
+        let mut status: libc::c_int = 0;
+        let mut interval = REAP_POLL_INTERVAL;
+        loop {
+            let ret = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) };
+            if ret == pid as libc::pid_t {
+                break;
+            }
+            if ret == -1 && std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+                return Self::default();
+            }
+            tokio::time::sleep(interval).await;
+            interval = (interval * 2).min(MAX_REAP_POLL_INTERVAL);
+        }
+        if libc::WIFEXITED(status) {
+            Self { code: Some(libc::WEXITSTATUS(status)), signal: None }
+        } else if libc::WIFSIGNALED(status) {
+            Self { code: None, signal: Some(libc::WTERMSIG(status)) }
+        } else {
+            Self::default()
+        }
+    }
+}
+
+/// Shells that exit sooner than this after launch count as failing to start
+const FAST_FAILURE_WINDOW: Duration = Duration::from_secs(1);
+/// Consecutive fast failures after which a restart_on_exit terminal gives up
+const MAX_FAST_FAILURES: u32 = 5;
+
+/// Terminals alive in this process, across every registry
+static LIVE_TERMINALS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of terminals currently alive in this process
+pub fn live_count() -> usize {
+    LIVE_TERMINALS.load(Ordering::Relaxed)
+}
 
 /// A running terminal instance
 pub struct Terminal {
-    writer: Box<dyn Write + Send>,
-    master: Box<dyn MasterPty + Send>,
+    /// Non-blocking handle on the PTY master, shared with the output task
+    io: Arc<AsyncFd<OwnedFd>>,
+    /// Only touched through `&mut self`; the mutex just makes `Terminal` Sync
+    master: std::sync::Mutex<Box<dyn MasterPty + Send>>,
     _child: Box<dyn Child + Send + Sync>,
+    pid: u32,
+    /// Set once the output task has reaped the child
+    exited: watch::Sender<bool>,
+    /// Set once the client is gone but the terminal stays; output is then only logged
+    detached: Arc<AtomicBool>,
+    /// Task sending this terminal's output to the client
+    forwarder: Option<AbortHandle>,
+    /// This terminal's output channel, reused when it restarts
+    output_tx: mpsc::Sender<Vec<u8>>,
+    /// Launch parameters, kept for restart_on_exit (size tracks resizes)
+    spec: CreateRequest,
+    /// Name of the on-disk output log, reopened when the terminal restarts
+    log_name: Option<String>,
+    started_at: Instant,
+    fast_failures: u32,
 }
 
 impl Terminal {
-    /// Write data to the terminal's stdin
-    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
-        self.writer.write_all(data)
+    /// Open a PTY, launch the shell and start forwarding its output
+    fn spawn(
+        terminal_id: u32,
+        spec: CreateRequest,
+        output_tx: mpsc::Sender<Vec<u8>>,
+        exit_tx: mpsc::Sender<(u32, ExitStatus)>,
+        log: Option<OutputLog>,
+    ) -> Result<Self, BoxError> {
+        let pty_system = native_pty_system();
+        let pair = pty_system.openpty(PtySize {
+            rows: spec.rows,
+            cols: spec.cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+
+        let mut cmd = CommandBuilder::new(&spec.shell);
+        for arg in &spec.args {
+            cmd.arg(arg);
+        }
+        cmd.cwd(&spec.cwd);
+        for (k, v) in &spec.env {
+            cmd.env(k, v);
+        }
+        // The launcher's auth token is for clients, not for shells, and the
+        // launcher shouldn't mistake a service started from one for a sidecar
+        cmd.env_remove(crate::AUTH_TOKEN_ENV);
+        cmd.env_remove(crate::SIDECAR_DIR_ENV);
+
+        let child = pair.slave.spawn_command(cmd)?;
+        let pid = child.process_id().unwrap_or(0);
+        drop(pair.slave); // Close slave in parent process
+
+        let io = Arc::new(AsyncFd::new(nonblocking_dup(pair.master.as_raw_fd())?)?);
+        let (exited, _) = watch::channel(false);
+        let detached = Arc::new(AtomicBool::new(false));
+
+        // Forward PTY output from an async task; no thread is held per terminal
+        let (reader, exited_flag, chunks_tx) = (io.clone(), exited.clone(), output_tx.clone());
+        let detached_flag = detached.clone();
+        let overflow = spec.overflow;
+        let log_name = log.as_ref().map(|l| l.name().to_string());
+        let mut log = log.map(OutputLog::spawn_writer);
+        tokio::spawn(async move {
+            let mut buf = [0u8; OUTPUT_CHUNK_SIZE];
+            let mut dropped: usize = 0;
+            let mut forwarding = true;
+            loop {
+                let n = match read_ready(&reader, &mut buf).await {
+                    Ok(0) | Err(_) => break, // EOF, or EIO once the slave side closes
+                    Ok(n) => n,
+                };
+                // Log before the overflow policy so dropped output is still recoverable
+                if let Some(writer) = &log
+                    && !writer.write(&buf[..n]).await
+                {
+                    log = None;
+                }
+                if !forwarding {
+                    continue;
+                }
+                let chunk = buf[..n].to_vec();
+                let sent = match overflow {
+                    OverflowPolicy::Block => chunks_tx.send(chunk).await.is_ok(),
+                    OverflowPolicy::Drop => match chunks_tx.try_send(chunk) {
+                        Ok(()) => true,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            dropped += n;
+                            true
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => false,
+                    },
+                };
+                if !sent {
+                    // Keep reading for a detached terminal so its shell never blocks on a full PTY
+                    if !detached_flag.load(Ordering::Acquire) {
+                        break;
+                    }
+                    forwarding = false;
+                }
+            }
+            if dropped > 0 {
+                warn!(terminal_id, dropped, "Dropped terminal output on overflow");
+            }
+            if let Some(writer) = log {
+                writer.finish().await;
+            }
+            // Output is closed; reap the child so the client learns how it ended
+            let status = ExitStatus::wait(pid).await;
+            exited_flag.send_replace(true);
+            let _ = exit_tx.send((terminal_id, status)).await;
+        }.in_current_span());
+
+        LIVE_TERMINALS.fetch_add(1, Ordering::Relaxed);
+        Ok(Self {
+            io,
+            master: std::sync::Mutex::new(pair.master),
+            _child: child,
+            pid,
+            exited,
+            detached,
+            forwarder: None,
+            output_tx,
+            spec,
+            log_name,
+            started_at: Instant::now(),
+            fast_failures: 0,
+        })
+    }
+
+    /// Name of this terminal's output log, if logging is enabled
+    pub fn log_name(&self) -> Option<&str> {
+        self.log_name.as_deref()
+    }
+
+    /// A handle for writing to the terminal's stdin once the registry is unlocked
+    pub fn input(&self) -> TerminalInput {
+        TerminalInput(self.io.clone())
+    }
+
+    /// Read the child's current environment from /proc/<pid>/environ
+    pub fn environment(&self) -> std::io::Result<HashMap<String, String>> {
+        if self.pid == 0 {
+            return Err(std::io::Error::other("terminal has no process id"));
+        }
+        let raw = std::fs::read(format!("/proc/{}/environ", self.pid))?;
+        Ok(raw
+            .split(|b| *b == 0)
+            .filter_map(|entry| {
+                let entry = String::from_utf8_lossy(entry);
+                let (k, v) = entry.split_once('=')?;
+                Some((k.to_string(), v.to_string()))
+            })
+            .collect())
     }
 
     /// Resize the terminal
-    pub fn resize(&self, cols: u16, rows: u16) -> std::io::Result<()> {
-        self.master.resize(PtySize {
+    pub fn resize(&mut self, cols: u16, rows: u16) -> std::io::Result<()> {
+        let master = self.master.get_mut().unwrap_or_else(|e| e.into_inner());
+        master.resize(PtySize {
             rows,
             cols,
             pixel_width: 0,
             pixel_height: 0,
-        }).map_err(|e| std::io::Error::other(e.to_string()))
+        }).map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.spec.cols = cols;
+        self.spec.rows = rows;
+        Ok(())
+    }
+
+    /// Remember the task forwarding output to the client, so `detach` can stop it
+    pub fn set_forwarder(&mut self, forwarder: AbortHandle) {
+        self.forwarder = Some(forwarder);
+    }
+
+    /// Keep running without a client: output stops going to the connection
+    /// and is only written to the output log, if there is one
+    pub fn detach(&self) {
+        self.detached.store(true, Ordering::Release);
+        if let Some(forwarder) = &self.forwarder {
+            forwarder.abort();
+        }
+    }
+
+    /// Resolve once the shell has exited and been reaped
+    pub fn wait_exited(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut exited = self.exited.subscribe();
+        async move {
+            let _ = exited.wait_for(|exited| *exited).await;
+        }
+    }
+}
+
+/// Writes to a terminal's stdin; a shell that stops reading can keep a write
+/// waiting indefinitely, so this is held instead of the registry lock
+#[derive(Clone)]
+pub struct TerminalInput(Arc<AsyncFd<OwnedFd>>);
+
+impl TerminalInput {
+    pub async fn write(&self, data: &[u8]) -> std::io::Result<()> {
+        let mut written = 0;
+        while written < data.len() {
+            let mut guard = self.0.writable().await?;
+            match guard.try_io(|fd| raw_write(fd.as_raw_fd(), &data[written..])) {
+                Ok(result) => written += result?,
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Terminal {
+    /// Signal the shell's process group, unless the shell is already gone
+    fn signal_group(&self, signal: libc::c_int) {
+        if self.pid != 0 && !*self.exited.borrow() {
+            unsafe {
+                libc::kill(-(self.pid as libc::pid_t), signal);
+            }
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        LIVE_TERMINALS.fetch_sub(1, Ordering::Relaxed);
+        // The output task keeps the master open, so hang up the shell's process
+        // group explicitly, as closing a real terminal would
+        self.signal_group(libc::SIGHUP);
+    }
+}
+
+/// Open a pidfd for `pid`, which becomes readable once the process exits
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: u32) -> Option<AsyncFd<OwnedFd>> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return None; // ENOSYS before Linux 5.3
+    }
+    AsyncFd::new(unsafe { OwnedFd::from_raw_fd(fd as std::os::fd::RawFd) }).ok()
+}
+
+/// Duplicate the PTY master fd and switch it to non-blocking mode
+/// O_NONBLOCK is a file status flag, so it also applies to the original fd;
+/// that one is only used for resize ioctls, which don't block either way
+fn nonblocking_dup(fd: Option<std::os::fd::RawFd>) -> std::io::Result<OwnedFd> {
+    let fd = fd.ok_or_else(|| std::io::Error::other("PTY master has no file descriptor"))?;
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let owned = unsafe { OwnedFd::from_raw_fd(dup) };
+    let flags = unsafe { libc::fcntl(dup, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(dup, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(owned)
+}
+
+/// Read from the PTY once it's readable
+async fn read_ready(io: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let mut guard = io.readable().await?;
+        match guard.try_io(|fd| raw_read(fd.as_raw_fd(), buf)) {
+            Ok(result) => return result,
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+fn raw_read(fd: std::os::fd::RawFd, buf: &mut [u8]) -> std::io::Result<usize> {
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+    if n < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+fn raw_write(fd: std::os::fd::RawFd, data: &[u8]) -> std::io::Result<usize> {
+    let n = unsafe { libc::write(fd, data.as_ptr().cast(), data.len()) };
+    if n < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
     }
 }
 
@@ -34,6 +382,15 @@ pub struct TerminalRegistry {
     // id : terminal
     pub terminals: HashMap<u32, Terminal>,
     next_id: u32,
+    output_logs: Option<OutputLogConfig>,
+    /// Set once shutdown starts; exited terminals are no longer restarted
+    draining: bool,
+}
+
+impl Default for TerminalRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TerminalRegistry {
@@ -41,78 +398,114 @@ impl TerminalRegistry {
         Self {
             terminals: HashMap::new(),
             next_id: 1,
+            output_logs: None,
+            draining: false,
         }
     }
 
-    /// Create a new terminal with the given shell and dimensions
-    /// Returns (terminal_id, pid) on success
+    /// Tee every terminal's output to a log under `config.dir`
+    pub fn with_output_logs(mut self, config: OutputLogConfig) -> Self {
+        self.output_logs = Some(config);
+        self
+    }
+
+    /// Stop logging output for terminals created from now on, and serving stored logs
+    pub fn disable_output_logs(&mut self) {
+        self.output_logs = None;
+    }
+
+    /// Where output logs are kept, unless they are disabled for this client
+    pub fn output_logs(&self) -> Option<&OutputLogConfig> {
+        self.output_logs.as_ref()
+    }
+
+    /// Open the output log for a terminal, if logging is enabled
+    /// A log that can't be opened is reported and skipped rather than failing the terminal
+    fn open_log(&self, existing: Option<&str>) -> Option<OutputLog> {
+        let config = self.output_logs.as_ref()?;
+        let result = match existing {
+            Some(name) => OutputLog::open(config, name.to_string()),
+            None => OutputLog::create(config),
+        };
+        result
+            .inspect_err(|e| warn!(dir = %config.dir.display(), error = %e, "Failed to open output log"))
+            .ok()
+    }
+
+    /// Create a new terminal with the requested shell and dimensions
+    /// Returns (terminal_id, pid, output receiver) on success
     pub fn create(
         &mut self,
-        shell: &str,
-        args: &[String],
-        cwd: &str,
-        env: &HashMap<String, String>,
-        cols: u16,
-        rows: u16,
-        output_tx: mpsc::Sender<(u32, Vec<u8>)>,
-        exit_tx: mpsc::Sender<(u32, Option<i32>)>,
-    ) -> Result<(u32, u32), Box<dyn std::error::Error + Send + Sync>> {
-        let pty_system = native_pty_system();
-        let pair = pty_system.openpty(PtySize {
-            rows,
-            cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        })?;
+        req: &CreateRequest,
+        exit_tx: mpsc::Sender<(u32, ExitStatus)>,
+    ) -> Result<(u32, u32, mpsc::Receiver<Vec<u8>>), BoxError> {
+        let capacity = req.output_buffer.unwrap_or(DEFAULT_OUTPUT_BUFFER).clamp(1, MAX_OUTPUT_BUFFER);
+        let (output_tx, output_rx) = mpsc::channel(capacity);
 
-        let mut cmd = CommandBuilder::new(shell);
-        for arg in args {
-            cmd.arg(arg);
+        let id = self.next_id;
+        let terminal = Terminal::spawn(id, req.clone(), output_tx, exit_tx, self.open_log(None))?;
+        self.next_id += 1;
+
+        let pid = terminal.pid;
+        self.terminals.insert(id, terminal);
+        Ok((id, pid, output_rx))
+    }
+
+    /// Relaunch the shell of an exited terminal under the same id
+    /// Returns the new pid, or None when the terminal was killed, didn't ask to be
+    /// restarted, or keeps dying right after launch
+    pub fn restart(
+        &mut self,
+        id: u32,
+        exit_tx: mpsc::Sender<(u32, ExitStatus)>,
+    ) -> Result<Option<u32>, BoxError> {
+        let Some(old) = self.terminals.get(&id) else {
+            return Ok(None);
+        };
+        if !old.spec.restart_on_exit || self.draining {
+            return Ok(None);
         }
-        cmd.cwd(cwd);
-        for (k, v) in env {
-            cmd.env(k, v);
+        let fast_failures = if old.started_at.elapsed() < FAST_FAILURE_WINDOW {
+            old.fast_failures + 1
+        } else {
+            0
+        };
+        if fast_failures >= MAX_FAST_FAILURES {
+            return Ok(None);
         }
 
-        let child = pair.slave.spawn_command(cmd)?;
-        let pid = child.process_id().unwrap_or(0);
-        drop(pair.slave); // Close slave in parent process
+        let log = self.open_log(old.log_name.as_deref());
+        let mut terminal = Terminal::spawn(id, old.spec.clone(), old.output_tx.clone(), exit_tx, log)?;
+        terminal.fast_failures = fast_failures;
+        terminal.forwarder = old.forwarder.clone();
+        let pid = terminal.pid;
+        self.terminals.insert(id, terminal);
+        Ok(Some(pid))
+    }
 
-        let id = self.next_id;
-        self.next_id += 1;
+    /// Start shutting down: stop restarting terminals and forget ones that already exited
+    pub fn start_draining(&mut self) {
+        self.draining = true;
+        // Terminals that already exited won't report again
+        self.terminals.retain(|_, t| !*t.exited.borrow());
+    }
+
+    /// Hang up every terminal, as closing its window would
+    pub fn hang_up_all(&self) {
+        for terminal in self.terminals.values() {
+            terminal.signal_group(libc::SIGHUP);
+        }
+    }
 
-        let reader = pair.master.try_clone_reader()?;
-        let writer = pair.master.take_writer()?;
+    /// Kill terminals that ignored the hangup
+    pub fn kill_all(&self) {
+        for terminal in self.terminals.values() {
+            terminal.signal_group(libc::SIGKILL);
+        }
+    }
 
-        // Spawn blocking thread to read PTY output and forward to channel
-        let terminal_id = id;
-        tokio::task::spawn_blocking(move || {
-            let mut reader = reader;
-            let mut buf = [0u8; 4096];
-            loop {
-                match reader.read(&mut buf) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        if output_tx.blocking_send((terminal_id, buf[..n].to_vec())).is_err() {
-                            break;
-                        }
-                    }
-                    Err(_) => break,
-                }
-            }
-            let _ = exit_tx.blocking_send((terminal_id, None));
-        });
-
-        self.terminals.insert(
-            id,
-            Terminal {
-                writer,
-                master: pair.master,
-                _child: child,
-            },
-        );
-
-        Ok((id, pid))
+    pub fn is_draining(&self) -> bool {
+        self.draining
     }
 
     pub fn get_mut(&mut self, id: u32) -> Option<&mut Terminal> {
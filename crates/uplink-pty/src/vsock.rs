@@ -0,0 +1,100 @@
+//! AF_VSOCK listener for running inside VM guests
+//!
+//! Lets a host-side client reach the service in a Firecracker/QEMU guest
+//! without any guest networking. Tokio has no vsock types, so the sockets are
+//! driven through [`AsyncFd`].
+
+use socket2::{SockAddr, Socket};
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Accept connections from any CID (`VMADDR_CID_ANY`)
+pub const CID_ANY: u32 = u32::MAX;
+
+/// Pending connections the kernel queues before `accept`
+const BACKLOG: i32 = 128;
+
+/// A listening vsock socket
+pub struct VsockListener {
+    inner: AsyncFd<Socket>,
+}
+
+impl VsockListener {
+    pub fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let socket = vsock_socket()?;
+        socket.bind(&SockAddr::vsock(cid, port))?;
+        socket.listen(BACKLOG)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { inner: AsyncFd::new(socket)? })
+    }
+
+    /// Accept a connection, returning it with the peer's CID
+    pub async fn accept(&self) -> io::Result<(VsockStream, u32)> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|socket| socket.get_ref().accept()) {
+                Ok(result) => {
+                    let (socket, addr) = result?;
+                    socket.set_nonblocking(true)?;
+                    let cid = addr.as_vsock_address().map_or(CID_ANY, |(cid, _)| cid);
+                    return Ok((VsockStream { inner: AsyncFd::new(socket)? }, cid));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn vsock_socket() -> io::Result<Socket> {
+    Socket::new(socket2::Domain::VSOCK, socket2::Type::STREAM, None)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn vsock_socket() -> io::Result<Socket> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "vsock is Linux-only"))
+}
+
+/// A connected vsock socket
+pub struct VsockStream {
+    inner: AsyncFd<Socket>,
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|socket| socket.get_ref().read(unfilled)) {
+                Ok(result) => {
+                    buf.advance(result?);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+            match guard.try_io(|socket| socket.get_ref().write(data)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.get_ref().shutdown(std::net::Shutdown::Write))
+    }
+}
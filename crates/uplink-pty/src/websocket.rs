@@ -0,0 +1,150 @@
+//! WebSocket transport for browser clients
+//!
+//! Carries the same frames as the Unix socket, one frame per binary message.
+//! Each connection is bridged onto an in-memory stream and served with
+//! [`PtyServer::serve_on`], so every protocol feature works unchanged.
+//!
+//! Any page open in the user's browser can reach a loopback port, so the
+//! gateway only starts with an auth token configured, and upgrades carrying an
+//! `Origin` outside [`Config::websocket_origins`](crate::Config::websocket_origins)
+//! are refused with 403. Clients that send no `Origin` are not browsers and
+//! still have to present the token.
+
+use crate::{wait_shutdown, PtyServer};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::codec::{Encoder, FramedRead};
+use tracing::{debug, error, info, warn, Instrument};
+use uplink_proto::FrameCodec;
+
+/// Buffer between the WebSocket pump and the protocol handler
+const BRIDGE_BUFFER: usize = 64 * 1024;
+
+impl PtyServer {
+    /// Accept WebSocket clients on `addr` until shutdown
+    pub async fn run_websocket(&self, addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.config.auth_token.is_none() {
+            return Err("the WebSocket gateway requires an auth token".into());
+        }
+        let listener = TcpListener::bind(addr).await?;
+        info!(addr = %listener.local_addr()?, "WebSocket gateway listening");
+
+        let mut shutdown = self.shutdown.subscribe();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = wait_shutdown(&mut shutdown) => return Ok(()),
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!(error = %e, "WebSocket accept error");
+                    continue;
+                }
+            };
+            let Some(permit) = self.admit() else {
+                continue;
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                info!(%peer, "WebSocket client connected");
+                if let Err(e) = server.serve_websocket(stream).await {
+                    error!(%peer, error = %e, "WebSocket client error");
+                }
+                info!(%peer, "WebSocket client disconnected");
+                drop(permit);
+            }.instrument(crate::info::connection_span()));
+        }
+    }
+
+    async fn serve_websocket(&self, stream: TcpStream) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let check = OriginCheck { allowed: self.config.websocket_origins.clone() };
+        let ws = tokio_tungstenite::accept_hdr_async(stream, check).await?;
+        let (mut ws_tx, mut ws_rx) = ws.split();
+        let (service_side, bridge_side) = tokio::io::duplex(BRIDGE_BUFFER);
+        let (bridge_read, mut bridge_write) = tokio::io::split(bridge_side);
+
+        // Client to server: binary messages are written through as-is
+        let inbound = async move {
+            while let Some(msg) = ws_rx.next().await {
+                match msg? {
+                    Message::Binary(data) => bridge_write.write_all(&data).await?,
+                    Message::Close(_) => break,
+                    other => debug!(kind = ?other, "Ignoring non-binary WebSocket message"),
+                }
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+        };
+
+        // Server to client: re-frame the byte stream so each frame is one message
+        let outbound = async move {
+            let mut frames = FramedRead::new(bridge_read, FrameCodec::new());
+            let mut codec = FrameCodec::new();
+            let (code, reason) = loop {
+                let frame = match frames.next().await {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(e)) => {
+                        error!(error = %e, "Failed to re-frame server output for the WebSocket");
+                        break (CloseCode::Error, "internal error");
+                    }
+                    None => break (CloseCode::Normal, ""),
+                };
+                let mut buf = bytes::BytesMut::new();
+                if let Err(e) = codec.encode(frame, &mut buf) {
+                    error!(error = %e, "Failed to encode a frame for the WebSocket");
+                    break (CloseCode::Error, "internal error");
+                }
+                if let Err(e) = ws_tx.send(Message::Binary(buf.to_vec())).await {
+                    debug!(error = %e, "WebSocket write failed");
+                    return;
+                }
+            };
+            // Tell the client why, rather than leaving it waiting on a dead stream
+            let close = CloseFrame { code, reason: reason.into() };
+            let _ = ws_tx.send(Message::Close(Some(close))).await;
+            let _ = ws_tx.close().await;
+        };
+
+        tokio::spawn(outbound.in_current_span());
+        // Messages are re-framed by header, so multiplexing isn't offered here
+        let serve = self.serve(service_side, false);
+        tokio::pin!(serve);
+        tokio::select! {
+            r = &mut serve => r,
+            r = inbound => {
+                if let Err(e) = r {
+                    debug!(error = %e, "WebSocket read ended");
+                }
+                // The bridge's write half is gone, so the handler sees EOF and cleans up
+                serve.await
+            }
+        }
+    }
+}
+
+/// Refuses browser upgrades from origins that weren't allowed
+struct OriginCheck {
+    allowed: Vec<String>,
+}
+
+impl Callback for OriginCheck {
+    fn on_request(self, req: &Request, resp: Response) -> Result<Response, ErrorResponse> {
+        let Some(origin) = req.headers().get("origin") else {
+            return Ok(resp);
+        };
+        let origin = origin.to_str().unwrap_or_default();
+        if self.allowed.iter().any(|a| a.eq_ignore_ascii_case(origin)) {
+            return Ok(resp);
+        }
+        warn!(origin, "Refusing WebSocket upgrade from a foreign origin");
+        let mut resp = ErrorResponse::new(Some("origin not allowed".to_string()));
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        Err(resp)
+    }
+}
@@ -0,0 +1,50 @@
+//! uplink: every sidecar service in one multi-call binary
+//!
+//! `uplink pty …` takes the same options as `uplink-pty`. `uplink all …` runs
+//! every service built into this binary on one runtime with one log; for now
+//! that is only the pty service, as uplink-fs lives outside this workspace.
+
+use tracing::{error, info};
+use uplink_pty::cli;
+
+const USAGE: &str = "usage: uplink <pty|fs|all> [options]";
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprintln!("{USAGE}");
+        std::process::exit(2);
+    };
+    match command.as_str() {
+        "pty" | "all" => {}
+        "fs" => {
+            eprintln!("uplink: the fs service is not built into this binary");
+            std::process::exit(2);
+        }
+        "-h" | "--help" => {
+            println!("{USAGE}");
+            return;
+        }
+        other => {
+            eprintln!("uplink: unknown command: {other}\n{USAGE}");
+            std::process::exit(2);
+        }
+    }
+
+    let _guard = cli::init_logging("uplink.log");
+    info!(command = %command, "uplink starting");
+
+    let args = match cli::Args::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            error!(error = %e, "Invalid arguments");
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = cli::run(args).await {
+        error!(error = %e, "Fatal error");
+        std::process::exit(1);
+    }
+}
@@ -6,6 +6,7 @@ use flate2::write::GzEncoder;
 use flate2::Compression;
 use serde::Deserialize;
 use tar::Builder;
+use uplink_server::manifest;
 
 #[derive(Deserialize)]
 struct ProductJson {
@@ -48,6 +49,11 @@ fn run() -> Result<(), Box<dyn std::error::Error>> {
     fs::copy(&args.launcher_bin, &server_bin_path)?;
     set_executable(&server_bin_path, &args.launcher_bin)?;
 
+    // Record hashes after everything is in place so the launcher can verify the install
+    let manifest = manifest::Manifest::generate(&args.build_dir)?;
+    manifest.write(&args.build_dir)?;
+    println!("Recorded {} files in {}", manifest.files.len(), manifest::MANIFEST_FILE);
+
     if let Some(parent) = args.out_path.parent() {
         fs::create_dir_all(parent)?;
     }
@@ -148,12 +154,11 @@ fn next_value(
 fn load_server_app_name(build_dir: &Path, manifest_dir: &Path) -> Option<String> {
     let candidates = [build_dir.join("product.json"), manifest_dir.join("vscode-server/product.json")];
     for candidate in candidates {
-        if let Ok(contents) = fs::read_to_string(candidate) {
-            if let Ok(product) = serde_json::from_str::<ProductJson>(&contents) {
-                if let Some(name) = product.server_application_name {
-                    return Some(name);
-                }
-            }
+        if let Ok(contents) = fs::read_to_string(candidate)
+            && let Ok(product) = serde_json::from_str::<ProductJson>(&contents)
+            && let Some(name) = product.server_application_name
+        {
+            return Some(name);
         }
     }
     None
@@ -0,0 +1,4 @@
+//! Shared code for the uplink-server launcher and packager
+
+pub mod manifest;
+pub mod supervisor;
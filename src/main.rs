@@ -1,9 +1,25 @@
 use std::env;
 use std::ffi::OsString;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+use uplink_server::manifest::{self, Manifest};
+use uplink_server::supervisor;
+
+/// Per-stage shutdown timeout when UPLINK_SHUTDOWN_TIMEOUT isn't set
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Shared secret uplink-pty requires from its clients; node and its children inherit it
+const PTY_TOKEN_ENV: &str = "UPLINK_PTY_TOKEN";
 
 fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(std::io::stderr)
+        .init();
     match run() {
         Ok(code) => std::process::exit(code),
         Err(err) => {
@@ -19,6 +35,16 @@ fn run() -> Result<i32, Box<dyn std::error::Error>> {
         args.remove(0);
     }
 
+    let exe_path = env::current_exe()?.canonicalize()?;
+    let bin_dir = exe_path
+        .parent()
+        .ok_or("failed to resolve launcher binary directory")?;
+    let root = bin_dir.parent().ok_or("failed to resolve server root")?;
+
+    if args.first().is_some_and(|first| first == "verify") {
+        return verify_install(root);
+    }
+
     let inspect_arg = if let Some(first) = args.first() {
         let first_str = first.to_string_lossy();
         if first_str.starts_with("--inspect") {
@@ -30,12 +56,6 @@ fn run() -> Result<i32, Box<dyn std::error::Error>> {
         None
     };
 
-    let exe_path = env::current_exe()?.canonicalize()?;
-    let bin_dir = exe_path
-        .parent()
-        .ok_or("failed to resolve launcher binary directory")?;
-    let root = bin_dir.parent().ok_or("failed to resolve server root")?;
-
     let node_path = root.join("node");
     let server_main = root.join("out").join("server-main.js");
     if !node_path.exists() {
@@ -45,19 +65,120 @@ fn run() -> Result<i32, Box<dyn std::error::Error>> {
         return Err(format!("server entrypoint not found at {}", server_main.display()).into());
     }
 
-    maybe_patch_glibc(&node_path);
+    let patched = maybe_patch_glibc(&node_path);
+    maybe_start_scrubber(root, patched);
 
     let mut cmd = Command::new(&node_path);
     if let Some(inspect) = inspect_arg {
         cmd.arg(inspect);
     }
     cmd.arg(server_main).args(args);
+    if env::var_os(PTY_TOKEN_ENV).is_none() {
+        cmd.env(PTY_TOKEN_ENV, generate_token()?);
+    }
 
-    let status = cmd.status()?;
+    let status = supervisor::run(cmd, shutdown_timeout())?;
     Ok(status.code().unwrap_or(1))
 }
 
-fn maybe_patch_glibc(node_path: &Path) {
+/// How long each shutdown stage may take, from UPLINK_SHUTDOWN_TIMEOUT (seconds)
+fn shutdown_timeout() -> Duration {
+    env::var("UPLINK_SHUTDOWN_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map_or(DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs)
+}
+
+/// 32 random bytes from /dev/urandom, hex encoded
+fn generate_token() -> std::io::Result<String> {
+    let mut bytes = [0u8; 32];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// `uplink-server verify`: check the install against its manifest once
+fn verify_install(root: &Path) -> Result<i32, Box<dyn std::error::Error>> {
+    let manifest = Manifest::load(root)
+        .map_err(|e| format!("failed to load {}: {e}", root.join(manifest::MANIFEST_FILE).display()))?;
+    let drift = manifest.verify(root);
+    for entry in &drift {
+        println!("{entry}");
+    }
+    if drift.is_empty() {
+        println!("{} files verified", manifest.files.len());
+        Ok(0)
+    } else {
+        println!("{} of {} files drifted", drift.len(), manifest.files.len());
+        Ok(1)
+    }
+}
+
+/// Re-check the install in the background every UPLINK_VERIFY_INTERVAL seconds
+/// `patched_node` means node was just rewritten by patchelf, so its packaged hash no longer applies
+fn maybe_start_scrubber(root: &Path, patched_node: bool) {
+    let Some(interval) = env::var("UPLINK_VERIFY_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+    else {
+        return;
+    };
+    let mut manifest = match Manifest::load(root) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            warn!(error = %err, "Install scrubber disabled");
+            return;
+        }
+    };
+    if patched_node && let Err(err) = manifest.rehash(root, "node") {
+        warn!(error = %err, "Failed to re-hash patched node");
+    }
+    let metrics_file = env::var_os("UPLINK_VERIFY_METRICS_FILE").map(PathBuf::from);
+
+    let root: PathBuf = root.to_path_buf();
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(Duration::from_secs(interval));
+            let drift = manifest.verify(&root);
+            if let Some(path) = &metrics_file
+                && let Err(err) = write_drift_metrics(path, drift.len(), manifest.files.len())
+            {
+                warn!(path = %path.display(), error = %err, "Failed to write install metrics");
+            }
+            if drift.is_empty() {
+                continue;
+            }
+            let files: Vec<String> = drift.iter().map(ToString::to_string).collect();
+            warn!(drifted = drift.len(), total = manifest.files.len(), ?files, "Install integrity check found drift");
+        }
+    });
+}
+
+/// Write the last check's result for the node_exporter textfile collector
+/// Goes through a temporary file so a scrape never sees half of it
+fn write_drift_metrics(path: &Path, drifted: usize, total: usize) -> std::io::Result<()> {
+    let checked = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let body = format!(
+        "# HELP uplink_install_drifted_files Files that no longer match the install manifest.\n\
+         # TYPE uplink_install_drifted_files gauge\n\
+         uplink_install_drifted_files {drifted}\n\
+         # HELP uplink_install_files Files listed in the install manifest.\n\
+         # TYPE uplink_install_files gauge\n\
+         uplink_install_files {total}\n\
+         # HELP uplink_install_last_check_timestamp_seconds When the install was last checked.\n\
+         # TYPE uplink_install_last_check_timestamp_seconds gauge\n\
+         uplink_install_last_check_timestamp_seconds {checked}\n"
+    );
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, body)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Point node at a custom glibc when the VSCODE_SERVER_* variables ask for it
+/// Returns whether node was patched
+fn maybe_patch_glibc(node_path: &Path) -> bool {
     let glibc_linker = env::var_os("VSCODE_SERVER_CUSTOM_GLIBC_LINKER");
     let glibc_path = env::var_os("VSCODE_SERVER_CUSTOM_GLIBC_PATH");
     let patchelf_path = env::var_os("VSCODE_SERVER_PATCHELF_PATH");
@@ -65,7 +186,7 @@ fn maybe_patch_glibc(node_path: &Path) {
     let (Some(glibc_linker), Some(glibc_path), Some(patchelf_path)) =
         (glibc_linker, glibc_path, patchelf_path)
     else {
-        return;
+        return false;
     };
 
     println!(
@@ -79,7 +200,7 @@ fn maybe_patch_glibc(node_path: &Path) {
         .arg(node_path)
         .status()
     {
-        eprintln!("patchelf --set-rpath failed: {err}");
+        warn!(error = %err, "patchelf --set-rpath failed");
     }
 
     println!(
@@ -93,8 +214,10 @@ fn maybe_patch_glibc(node_path: &Path) {
         .arg(node_path)
         .status()
     {
-        eprintln!("patchelf --set-interpreter failed: {err}");
+        warn!(error = %err, "patchelf --set-interpreter failed");
     }
 
     println!("Patching complete.");
+    info!(node = %node_path.display(), "Patched node for custom glibc");
+    true
 }
@@ -0,0 +1,119 @@
+//! Install manifest: SHA-256 hashes of the files that make up a server install
+//!
+//! Written by the packager next to the bundled server and checked by the
+//! launcher to catch disk corruption and partially applied upgrades.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const MANIFEST_FILE: &str = "install-manifest.json";
+
+/// Top-level entries covered by the manifest, relative to the install root
+const COVERED: &[&str] = &["node", "out", "bin"];
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    /// Relative path -> hex-encoded SHA-256
+    pub files: BTreeMap<String, String>,
+}
+
+/// A file that no longer matches the manifest
+pub enum Drift {
+    Missing(String),
+    Modified(String),
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Drift::Missing(path) => write!(f, "missing: {path}"),
+            Drift::Modified(path) => write!(f, "modified: {path}"),
+        }
+    }
+}
+
+impl Manifest {
+    /// Hash every covered file under the install root
+    pub fn generate(root: &Path) -> io::Result<Self> {
+        let mut files = BTreeMap::new();
+        for entry in COVERED {
+            let path = root.join(entry);
+            if path.exists() {
+                collect(root, &path, &mut files)?;
+            }
+        }
+        Ok(Self { files })
+    }
+
+    pub fn load(root: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(root.join(MANIFEST_FILE))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn write(&self, root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(root.join(MANIFEST_FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Record the current hash of a listed file that the launcher changes on
+    /// purpose, such as `node` after patchelf
+    pub fn rehash(&mut self, root: &Path, rel: &str) -> io::Result<()> {
+        if let Some(hash) = self.files.get_mut(rel) {
+            *hash = hash_file(&root.join(rel))?;
+        }
+        Ok(())
+    }
+
+    /// Re-hash every listed file and report the ones that drifted
+    pub fn verify(&self, root: &Path) -> Vec<Drift> {
+        let mut drift = Vec::new();
+        for (rel, expected) in &self.files {
+            match hash_file(&root.join(rel)) {
+                Ok(actual) if &actual == expected => {}
+                Ok(_) => drift.push(Drift::Modified(rel.clone())),
+                Err(_) => drift.push(Drift::Missing(rel.clone())),
+            }
+        }
+        drift
+    }
+}
+
+fn collect(root: &Path, path: &Path, files: &mut BTreeMap<String, String>) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            collect(root, &entry?.path(), files)?;
+        }
+    } else if meta.is_file() {
+        let rel = path
+            .strip_prefix(root)
+            .map_err(io::Error::other)?
+            .to_string_lossy()
+            .into_owned();
+        files.insert(rel, hash_file(path)?);
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
@@ -0,0 +1,186 @@
+//! Running node under the launcher and shutting it down in order
+//!
+//! Node runs in its own process group. The sidecars it starts (uplink-pty and
+//! friends) write their pid into the directory named by `UPLINK_SIDECAR_DIR`,
+//! so they can be told apart from node's other children. On SIGTERM or SIGINT
+//! the launcher:
+//!   1. asks the registered sidecars to drain (SIGTERM; uplink-pty hangs up its
+//!      terminals and tells its client through lifecycle events),
+//!   2. stops node (SIGTERM),
+//!   3. once node is gone, kills whatever is left in its group.
+//!
+//! Each stage waits up to the shutdown timeout and is logged.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Directory sidecars register their pid in; matches uplink-pty's SIDECAR_DIR_ENV
+const SIDECAR_DIR_ENV: &str = "UPLINK_SIDECAR_DIR";
+
+/// How often the child and group are polled
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Signal that requested shutdown, or 0
+static SHUTDOWN_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    SHUTDOWN_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Start `cmd` in its own process group and wait for it, handling shutdown signals
+/// `timeout` bounds each shutdown stage
+pub fn run(mut cmd: Command, timeout: Duration) -> io::Result<ExitStatus> {
+    unsafe {
+        libc::signal(libc::SIGTERM, on_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_signal as *const () as libc::sighandler_t);
+    }
+
+    let sidecar_dir = create_sidecar_dir()?;
+    cmd.env(SIDECAR_DIR_ENV, &sidecar_dir);
+    let result = supervise(cmd, &sidecar_dir, timeout);
+    let _ = fs::remove_dir_all(&sidecar_dir);
+    result
+}
+
+fn supervise(mut cmd: Command, sidecar_dir: &Path, timeout: Duration) -> io::Result<ExitStatus> {
+    let mut child = cmd.process_group(0).spawn()?;
+    let pgid = child.id() as libc::pid_t;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            // Node is gone; don't leave its sidecars behind
+            stop_group(pgid, timeout);
+            return Ok(status);
+        }
+        let signal = SHUTDOWN_SIGNAL.load(Ordering::SeqCst);
+        if signal != 0 {
+            return shutdown(&mut child, pgid, sidecar_dir, signal, timeout);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn shutdown(child: &mut Child, pgid: libc::pid_t, sidecar_dir: &Path, signal: i32, timeout: Duration) -> io::Result<ExitStatus> {
+    info!(signal, "Shutting down");
+
+    let sidecars = registered_sidecars(sidecar_dir, pgid);
+    if !sidecars.is_empty() {
+        info!(?sidecars, "Draining sidecars");
+        for pid in &sidecars {
+            unsafe { libc::kill(*pid, libc::SIGTERM) };
+        }
+        if wait_until(timeout, || sidecars.iter().all(|pid| !is_running(*pid))) {
+            info!("Sidecars stopped");
+        } else {
+            warn!(timeout_secs = timeout.as_secs(), "Sidecars still running after timeout");
+        }
+    }
+
+    info!(pid = pgid, "Stopping node");
+    unsafe { libc::kill(pgid, libc::SIGTERM) };
+    let mut status = None;
+    let stopped = wait_until(timeout, || {
+        status = child.try_wait().ok().flatten();
+        status.is_some()
+    });
+    if stopped {
+        info!("Node stopped");
+    } else {
+        warn!(timeout_secs = timeout.as_secs(), "Node still running after timeout, killing it");
+        unsafe { libc::kill(pgid, libc::SIGKILL) };
+    }
+    let status = match status {
+        Some(status) => status,
+        None => child.wait()?,
+    };
+
+    stop_group(pgid, timeout);
+    info!("Shutdown complete");
+    Ok(status)
+}
+
+/// Terminate anything still in node's process group, killing it after `timeout`
+/// Only called once node has exited, so everything left is an orphan of it
+fn stop_group(pgid: libc::pid_t, timeout: Duration) {
+    let left = group_members(pgid);
+    if left.is_empty() {
+        return;
+    }
+    info!(pgid, processes = ?left, "Stopping leftover processes in node's group");
+    unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    if !wait_until(timeout, || group_members(pgid).is_empty()) {
+        let left = group_members(pgid);
+        warn!(pgid, processes = ?left, "Killing stragglers");
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    }
+}
+
+/// A fresh directory, private to this user, for sidecars to register in
+fn create_sidecar_dir() -> io::Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("uplink-sidecars-{}", std::process::id()));
+    // Left behind by an earlier launcher that had this pid
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Pids registered in `dir` that are still running in node's group
+/// Checking the group keeps a reused pid from being signalled
+fn registered_sidecars(dir: &Path, pgid: libc::pid_t) -> Vec<libc::pid_t> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<libc::pid_t>().ok())
+        .filter(|pid| matches!(proc_stat(*pid), Some((state, group)) if state != 'Z' && group == pgid))
+        .collect()
+}
+
+/// Poll `done` until it holds or `timeout` passes
+fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if done() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Live (non-zombie) processes in a process group, from /proc
+fn group_members(pgid: libc::pid_t) -> Vec<libc::pid_t> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<libc::pid_t>().ok())
+        .filter(|pid| matches!(proc_stat(*pid), Some((state, group)) if state != 'Z' && group == pgid))
+        .collect()
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+    matches!(proc_stat(pid), Some((state, _)) if state != 'Z')
+}
+
+/// State and process group of a process, from /proc/<pid>/stat
+fn proc_stat(pid: libc::pid_t) -> Option<(char, libc::pid_t)> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name is parenthesised and may contain spaces
+    let rest = &stat[stat.rfind(')')? + 2..];
+    let mut fields = rest.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let _ppid = fields.next()?;
+    let pgrp = fields.next()?.parse().ok()?;
+    Some((state, pgrp))
+}
@@ -34,15 +34,17 @@ let uplinkPtyProcess: ChildProcess | null = null;
 
 /** Start the uplink-pty Rust service */
 async function startUplinkPty(logService: { info: (msg: string) => void; error: (msg: string, err?: any) => void }): Promise<void> {
-	// Find uplink-pty binary: check env var first, then fall back to relative path
+	// UPLINK_PTY_PATH points at a standalone uplink-pty binary; otherwise run
+	// the pty service of the bundled multi-call `uplink` binary
 	let uplinkPtyPath = process.env.UPLINK_PTY_PATH;
+	let uplinkPtyArgs: string[] = [];
 
 	if (!uplinkPtyPath) {
-		// Fall back to relative path from build output
 		const currentFile = fileURLToPath(import.meta.url);
 		const currentDir = path.dirname(currentFile);
 		const serverRoot = path.resolve(currentDir, '../../../../..');
-		uplinkPtyPath = path.join(serverRoot, 'bin', 'uplink-pty');
+		uplinkPtyPath = path.join(serverRoot, 'bin', 'uplink');
+		uplinkPtyArgs = ['pty'];
 	}
 
 	console.log(`[uplink-pty] Binary path: ${uplinkPtyPath}`);
@@ -55,7 +57,7 @@ async function startUplinkPty(logService: { info: (msg: string) => void; error:
 	return new Promise((resolve, reject) => {
 		let timeoutId: ReturnType<typeof setTimeout> | null = null;
 
-		uplinkPtyProcess = spawn(uplinkPtyPath, [], {
+		uplinkPtyProcess = spawn(uplinkPtyPath, uplinkPtyArgs, {
 			stdio: ['ignore', 'pipe', 'pipe'],
 			detached: false
 		});
@@ -1,6 +1,7 @@
 /*---------------------------------------------------------------------------------------------
  * uplink-pty client: Communicates with Rust PTY service over Unix socket
  * Wire format: [1 byte tag][4 byte length BE][MessagePack payload]
+ * Responses are matched to requests by id; the server may answer out of order
  *--------------------------------------------------------------------------------------------*/
 
 import * as net from 'net';
@@ -12,11 +13,18 @@ const MSG_CREATE = 1;
 const MSG_INPUT = 2;
 const MSG_RESIZE = 3;
 const MSG_KILL = 4;
+const MSG_PING = 6;
+const MSG_AUTH = 43;
+const MSG_SERVER_INFO = 45;
 const MSG_CREATED = 10;
 const MSG_OK = 11;
 const MSG_ERROR = 12;
+const MSG_PONG = 13;
+const MSG_PROTOCOL_ERROR = 19;
 const MSG_DATA = 20;
 const MSG_EXIT = 21;
+const MSG_LIFECYCLE = 23;
+const MSG_SERVER_INFO_RESULT = 51;
 
 export interface CreateRequest {
 	id: number;
@@ -34,6 +42,25 @@ export interface CreatedResponse {
 	pid: number;
 }
 
+export interface ServerInfo {
+	id: number;
+	name: string;
+	version: string;
+	os: string;
+	arch: string;
+	git_hash?: string | null;
+	pid: number;
+	uptime_ms: number;
+	connections: number;
+	terminals: number;
+	memory_rss?: number | null;
+}
+
+export interface LifecycleEvent {
+	stage: 'draining' | 'force_killed' | 'stopped';
+	terminals: number;
+}
+
 export interface DataEvent {
 	terminal_id: number;
 	data: Uint8Array;
@@ -42,8 +69,34 @@ export interface DataEvent {
 export interface ExitEvent {
 	terminal_id: number;
 	code: number | null;
+	signal?: number | null;
+}
+
+/** Stable codes sent with MSG_ERROR; older servers send none (0) */
+export const enum UplinkErrorCode {
+	Unknown = 0,
+	Internal = 1,
+	UnsupportedVersion = 2,
+	AuthFailed = 3,
+	NotFound = 4,
+	Denied = 5,
+	Timeout = 6,
+	Disabled = 7,
+	Io = 8,
+	SpawnFailed = 9,
 }
 
+/** A request the server answered with MSG_ERROR; branch on `code`, show `message` */
+export class UplinkError extends Error {
+	constructor(message: string, readonly code: UplinkErrorCode) {
+		super(message);
+		this.name = 'UplinkError';
+	}
+}
+
+/** How long the server may stay silent once it has started pinging us */
+const HEARTBEAT_TIMEOUT_MS = 30_000;
+
 type PendingRequest = {
 	resolve: (value: any) => void;
 	reject: (error: Error) => void;
@@ -54,15 +107,19 @@ export class UplinkPtyClient extends EventEmitter {
 	private buffer: Buffer = Buffer.alloc(0);
 	private nextId = 1;
 	private pending = new Map<number, PendingRequest>();
+	private lastSeen = Date.now();
+	private heartbeatTimer: ReturnType<typeof setInterval> | null = null;
 
 	constructor(private socketPath: string) {
 		super();
 	}
 
 	async connect(): Promise<void> {
-		return new Promise((resolve, reject) => {
+		await new Promise<void>((resolve, reject) => {
 			let connected = false;
-			this.socket = net.createConnection(this.socketPath, () => {
+			// `abstract:name` is a Linux abstract socket, which Node addresses with a leading NUL
+			const path = this.socketPath.startsWith('abstract:') ? '\0' + this.socketPath.slice('abstract:'.length) : this.socketPath;
+			this.socket = net.createConnection(path, () => {
 				connected = true;
 				resolve();
 			});
@@ -76,6 +133,7 @@ export class UplinkPtyClient extends EventEmitter {
 			});
 
 			this.socket.on('close', () => {
+				this.stopHeartbeat();
 				this.emit('close');
 			});
 
@@ -83,6 +141,14 @@ export class UplinkPtyClient extends EventEmitter {
 				this.handleData(chunk);
 			});
 		});
+
+		// Generated by the uplink-server launcher and inherited through node and the
+		// pty host, like uplink-pty itself; must be the first request
+		const token = process.env.UPLINK_PTY_TOKEN;
+		if (token) {
+			const id = this.nextId++;
+			await this.request(MSG_AUTH, { id, token }, id);
+		}
 	}
 
 	private handleData(chunk: Buffer): void {
@@ -106,14 +172,26 @@ export class UplinkPtyClient extends EventEmitter {
 	private handleMessage(tag: number, payload: Buffer): void {
 		const msg = decode(payload) as any;
 		console.log(`[UplinkPtyClient] handleMessage tag=${tag}`, msg);
+		this.lastSeen = Date.now();
 
 		switch (tag) {
+			case MSG_PING: {
+				this.send(MSG_PONG, { seq: msg.seq });
+				this.startHeartbeat();
+				break;
+			}
 			case MSG_CREATED: {
 				const pending = this.pending.get(msg.id);
 				pending?.resolve(msg);
 				this.pending.delete(msg.id);
 				break;
 			}
+			case MSG_SERVER_INFO_RESULT: {
+				const pending = this.pending.get(msg.id);
+				pending?.resolve(msg);
+				this.pending.delete(msg.id);
+				break;
+			}
 			case MSG_OK: {
 				const pending = this.pending.get(msg.id);
 				pending?.resolve(msg);
@@ -122,16 +200,30 @@ export class UplinkPtyClient extends EventEmitter {
 			}
 			case MSG_ERROR: {
 				const pending = this.pending.get(msg.id);
-				pending?.reject(new Error(msg.message));
+				pending?.reject(new UplinkError(msg.message, msg.code ?? UplinkErrorCode.Unknown));
 				this.pending.delete(msg.id);
 				break;
 			}
+			case MSG_PROTOCOL_ERROR: {
+				// The frame with this tag was rejected outright; fail its request if the server could read the id
+				console.error(`[UplinkPtyClient] protocol error for tag ${msg.tag}: ${msg.message}`);
+				if (typeof msg.id === 'number') {
+					this.pending.get(msg.id)?.reject(new UplinkError(msg.message, UplinkErrorCode.Unknown));
+					this.pending.delete(msg.id);
+				}
+				this.emit('protocolError', msg.tag, msg.message);
+				break;
+			}
 			case MSG_DATA: {
 				this.emit('data', msg.terminal_id, Buffer.from(msg.data));
 				break;
 			}
 			case MSG_EXIT: {
-				this.emit('exit', msg.terminal_id, msg.code);
+				this.emit('exit', msg.terminal_id, msg.code, msg.signal ?? null);
+				break;
+			}
+			case MSG_LIFECYCLE: {
+				this.emit('lifecycle', msg as LifecycleEvent);
 				break;
 			}
 		}
@@ -176,7 +268,33 @@ export class UplinkPtyClient extends EventEmitter {
 		await this.request(MSG_KILL, { id, terminal_id: terminalId }, id);
 	}
 
+	/** Version, uptime, load and memory of the server process */
+	async serverInfo(): Promise<ServerInfo> {
+		const id = this.nextId++;
+		return this.request<ServerInfo>(MSG_SERVER_INFO, { id }, id);
+	}
+
+	/** Once the server pings, treat a silent connection as dead (suspended host, dropped tunnel) */
+	private startHeartbeat(): void {
+		if (this.heartbeatTimer) {
+			return;
+		}
+		this.heartbeatTimer = setInterval(() => {
+			if (Date.now() - this.lastSeen > HEARTBEAT_TIMEOUT_MS) {
+				this.socket?.destroy(new Error('uplink-pty stopped responding'));
+			}
+		}, HEARTBEAT_TIMEOUT_MS / 3);
+	}
+
+	private stopHeartbeat(): void {
+		if (this.heartbeatTimer) {
+			clearInterval(this.heartbeatTimer);
+			this.heartbeatTimer = null;
+		}
+	}
+
 	close(): void {
+		this.stopHeartbeat();
 		this.socket?.destroy();
 		this.socket = null;
 	}